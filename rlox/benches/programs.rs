@@ -0,0 +1,32 @@
+// End-to-end runs of the Lox programs in `benches/lox`, from source text to
+// the end of execution. Save a baseline and compare later commits with
+//
+//     cargo bench --bench programs -- --save-baseline before
+//     cargo bench --bench programs -- --baseline before
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use jlox_rust::Lox;
+
+const PROGRAMS: [&str; 6] = ["fib", "binary_trees", "string_building", "method_calls", "zoo", "loops"];
+
+fn programs(c: &mut Criterion) {
+    for name in PROGRAMS {
+        let path = format!("{}/benches/lox/{}.lox", env!("CARGO_MANIFEST_DIR"), name);
+        let source = std::fs::read_to_string(&path).unwrap();
+        c.bench_function(name, |b| {
+            b.iter(|| {
+                let mut lox = Lox::new();
+                lox.run(&source);
+                assert!(!lox.had_runtime_error);
+            })
+        });
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = programs
+}
+criterion_main!(benches);
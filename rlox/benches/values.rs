@@ -0,0 +1,64 @@
+// Compares value representations on a tight arithmetic loop: the boxed
+// `Rc<LoxValue>` the interpreter used to pass around, the inline
+// `LoxValue`, and (with `--features nan-boxing`) the NaN-boxed word that
+// scope slots use.
+//
+//     cargo bench --bench values [--features nan-boxing]
+
+use std::hint::black_box;
+use std::rc::Rc;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use jlox_rust::ast::LoxValue;
+
+const ITERATIONS: usize = 10_000;
+
+fn add(a: &LoxValue, b: &LoxValue) -> LoxValue {
+    match (a, b) {
+        (LoxValue::Number(a), LoxValue::Number(b)) => LoxValue::Number(a + b),
+        _ => LoxValue::Nil,
+    }
+}
+
+fn boxed() -> Rc<LoxValue> {
+    let mut sum = Rc::new(LoxValue::Number(0.0));
+    for i in 0..ITERATIONS {
+        let i = Rc::new(LoxValue::Number(i as f64));
+        sum = Rc::new(add(&sum, black_box(&i)));
+    }
+    sum
+}
+
+fn inline() -> LoxValue {
+    let mut sum = LoxValue::Number(0.0);
+    for i in 0..ITERATIONS {
+        let i = LoxValue::Number(i as f64);
+        sum = add(&sum, black_box(&i));
+    }
+    sum
+}
+
+#[cfg(feature = "nan-boxing")]
+fn nan_boxed() -> jlox_rust::nanbox::NanBox {
+    use jlox_rust::nanbox::NanBox;
+
+    let mut sum = NanBox::number(0.0);
+    for i in 0..ITERATIONS {
+        let i = NanBox::number(i as f64);
+        sum = NanBox::from(add(&sum.get(), black_box(&i.get())));
+    }
+    sum
+}
+
+fn values(c: &mut Criterion) {
+    let mut group = c.benchmark_group("values");
+    group.bench_function("boxed", |b| b.iter(boxed));
+    group.bench_function("inline", |b| b.iter(inline));
+    #[cfg(feature = "nan-boxing")]
+    group.bench_function("nan-boxed", |b| b.iter(nan_boxed));
+    group.finish();
+}
+
+criterion_group!(benches, values);
+criterion_main!(benches);
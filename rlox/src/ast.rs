@@ -2,6 +2,7 @@ use std::fmt::Debug;
 use std::rc::Rc;
 
 use crate::function::LoxCallable;
+use crate::module::Module;
 use crate::token::TokenLiteral;
 use crate::token::Token;
 use paste::paste;
@@ -44,20 +45,34 @@ pub type RcStmt = Rc<Stmt>;
 impl_build!( Expr, Binary, [ left: RcExpr, operator: Token, right: RcExpr ] );
 impl_build!( Expr, Group, [ expression: RcExpr ] );
 impl_build!( Expr, Unary, [ operator: Token, right: RcExpr ] );
-impl_build!( Expr, Literal, [ value: TokenLiteral ] );
+// `token` is the literal's own token; literals the parser adds have none.
+impl_build!( Expr, Literal, [ value: TokenLiteral, token: Option<Token> ] );
 impl_build!( Expr, Variable, [ name: Token ] );
 impl_build!( Expr, Assign, [ name: Token, value: RcExpr ] );
 impl_build!( Expr, Logical, [ left: RcExpr, operator: Token, right: RcExpr ] );
 impl_build!( Expr, Call, [ callee: RcExpr, paren: Token, arguments: Vec<RcExpr> ] );
+// `name += value` and friends, and `++`/`--` (prefix or postfix) with a
+// value of 1. Only a postfix update evaluates to the old value.
+impl_build!( Expr, Update, [ name: Token, operator: Token, value: RcExpr, postfix: bool ] );
+impl_build!( Expr, Conditional, [ condition: RcExpr, question: Token, then_branch: RcExpr, else_branch: RcExpr ] );
+// `object.name`; only modules have properties.
+impl_build!( Expr, Get, [ object: RcExpr, name: Token ] );
 
 impl_build!( Stmt, Expression, [ expression: RcExpr ] );
-impl_build!( Stmt, Print, [ expression: RcExpr ] );
-impl_build!( Stmt, Var, [ name: Token, initializer: Option<RcExpr> ] );
-impl_build!( Stmt, Block, [ statements: Vec<RcStmt> ] );
-impl_build!( Stmt, If, [ condition: RcExpr, then_branch: RcStmt, else_branch: Option<RcStmt> ] );
-impl_build!( Stmt, While, [ condition: RcExpr, body: RcStmt ] );
-impl_build!( Stmt, Function, [ name: Token, params: Vec<Token>, body: RcStmt ] );
+impl_build!( Stmt, Print, [ keyword: Token, expression: RcExpr ] );
+// `doc` is the text of the `///` comment lines just above the declaration.
+// `constant` for `const`, which always has an initializer.
+impl_build!( Stmt, Var, [ name: Token, initializer: Option<RcExpr>, doc: Option<Rc<str>>, constant: bool ] );
+// `brace` is the opening `{`; blocks the parser adds have none.
+impl_build!( Stmt, Block, [ statements: Vec<RcStmt>, brace: Option<Token> ] );
+impl_build!( Stmt, If, [ keyword: Token, condition: RcExpr, then_branch: RcStmt, else_branch: Option<RcStmt> ] );
+impl_build!( Stmt, While, [ keyword: Token, condition: RcExpr, body: RcStmt ] );
+impl_build!( Stmt, Function, [ name: Token, params: Vec<Token>, body: RcStmt, doc: Option<Rc<str>> ] );
 impl_build!( Stmt, Return, [ keyword: Token, value: Option<RcExpr> ] );
+// `import "path" as name;`, with `path` the string token.
+impl_build!( Stmt, Import, [ keyword: Token, path: Token, name: Token ] );
+// A top-level `var` or `fun` that importing scripts can reach.
+impl_build!( Stmt, Export, [ keyword: Token, declaration: RcStmt ] );
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub enum Expr {
@@ -69,6 +84,9 @@ pub enum Expr {
     Assign(Assign),
     Logical(Logical),
     Call(Call),
+    Update(Update),
+    Conditional(Conditional),
+    Get(Get),
 }
 
 
@@ -82,6 +100,59 @@ pub enum Stmt {
     While(While),
     Function(Function),
     Return(Return),
+    Import(Import),
+    Export(Export),
+}
+
+impl Expr {
+    // The first token the expression still carries, if any.
+    pub fn token(&self) -> Option<&Token> {
+        match self {
+            Expr::Binary(expr) => expr.left.token().or(Some(&expr.operator)),
+            Expr::Group(expr) => expr.expression.token(),
+            Expr::Literal(expr) => expr.token.as_ref(),
+            Expr::Unary(expr) => Some(&expr.operator),
+            Expr::Variable(expr) => Some(&expr.name),
+            Expr::Assign(expr) => Some(&expr.name),
+            Expr::Logical(expr) => expr.left.token().or(Some(&expr.operator)),
+            Expr::Call(expr) => expr.callee.token().or(Some(&expr.paren)),
+            Expr::Update(expr) if expr.postfix => Some(&expr.name),
+            Expr::Update(expr) => Some(&expr.operator),
+            Expr::Conditional(expr) => expr.condition.token().or(Some(&expr.question)),
+            Expr::Get(expr) => expr.object.token().or(Some(&expr.name)),
+        }
+    }
+
+    pub fn line(&self) -> Option<usize> {
+        self.token().map(|token| token.line)
+    }
+}
+
+impl Stmt {
+    // The token the statement is reported at: its keyword, or the name of
+    // a declaration. A block uses its first statement's.
+    pub fn token(&self) -> Option<&Token> {
+        match self {
+            Stmt::Expression(stmt) => stmt.expression.token(),
+            Stmt::Print(stmt) => Some(&stmt.keyword),
+            Stmt::Var(stmt) => Some(&stmt.name),
+            Stmt::Block(stmt) => stmt.statements.first().and_then(|stmt| stmt.token()),
+            Stmt::If(stmt) => Some(&stmt.keyword),
+            Stmt::While(stmt) => Some(&stmt.keyword),
+            Stmt::Function(stmt) => Some(&stmt.name),
+            Stmt::Return(stmt) => Some(&stmt.keyword),
+            Stmt::Import(stmt) => Some(&stmt.keyword),
+            Stmt::Export(stmt) => Some(&stmt.keyword),
+        }
+    }
+
+    // Line the statement starts on. Blocks have none of their own.
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Stmt::Block(_) => None,
+            stmt => stmt.token().map(|token| token.line),
+        }
+    }
 }
 
 pub trait ExprVisitor<R>
@@ -94,6 +165,9 @@ pub trait ExprVisitor<R>
     fn visit_assign(&self, expr: &Assign) -> R;
     fn visit_logical(&self, expr: &Logical) -> R;
     fn visit_call(&self, expr: &Call) -> R;
+    fn visit_update(&self, expr: &Update) -> R;
+    fn visit_conditional(&self, expr: &Conditional) -> R;
+    fn visit_get(&self, expr: &Get) -> R;
 }
 
 pub trait StmtVisitor<R>
@@ -106,6 +180,8 @@ pub trait StmtVisitor<R>
     fn visit_while(&self, stmt: &While) -> R;
     fn visit_function(&self, stmt: &Function) -> R;
     fn visit_return(&self, stmt: &Return) -> R;
+    fn visit_import(&self, stmt: &Import) -> R;
+    fn visit_export(&self, stmt: &Export) -> R;
 }
 
 pub trait ExprVisitable<R: ?Sized> {
@@ -165,27 +241,32 @@ macro_rules! impl_stmt_visitable {
 }
 
 
+// Numbers, booleans and nil are held inline and strings and callables
+// behind an `Rc`, so values are passed around by cloning rather than boxed.
+// Integer literals and arithmetic on them stay exact as `Int`; `Number` is
+// a float.
+#[derive(Default, Clone)]
 pub enum LoxValue {
+    Int(i64),
     Number(f64),
-    String(String),
+    String(Rc<str>),
     Bool(bool),
+    #[default]
     Nil,
-    Callable(Box<dyn for<'a> LoxCallable>),
+    Callable(Rc<dyn LoxCallable>),
+    Module(Rc<Module>),
 }
 
-impl Default for LoxValue {
-    fn default() -> Self {
-        LoxValue::Nil
-    }
-}
 
 impl PartialEq for LoxValue {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
+            (LoxValue::Int(a), LoxValue::Int(b)) => a == b,
             (LoxValue::Number(a), LoxValue::Number(b)) => a == b,
             (LoxValue::String(a), LoxValue::String(b)) => a == b,
             (LoxValue::Bool(a), LoxValue::Bool(b)) => a == b,
             (LoxValue::Nil, LoxValue::Nil) => true,
+            (LoxValue::Module(a), LoxValue::Module(b)) => Rc::ptr_eq(a, b),
             _ => false,
         }
     }   
@@ -194,12 +275,14 @@ impl PartialEq for LoxValue {
 impl std::fmt::Display for LoxValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            LoxValue::Int(n) => write!(f, "{}", n),
             LoxValue::Number(n) => write!(f, "{}", n),
             LoxValue::String(s) => write!(f, "{}", s),
             LoxValue::Bool(b) => write!(f, "{}", b),
             LoxValue::Nil => write!(f, "nil"),
             // LoxValue::Literal(l) => write!(f, "{}", l),
             LoxValue::Callable(c) => write!(f, "{}", c),
+            LoxValue::Module(m) => write!(f, "{}", m),
         }
     }
 }
@@ -207,11 +290,13 @@ impl std::fmt::Display for LoxValue {
 impl Debug for LoxValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Self::Int(arg0) => f.debug_tuple("Int").field(arg0).finish(),
             Self::Number(arg0) => f.debug_tuple("Number").field(arg0).finish(),
             Self::String(arg0) => f.debug_tuple("String").field(arg0).finish(),
             Self::Bool(arg0) => f.debug_tuple("Bool").field(arg0).finish(),
             Self::Nil => write!(f, "Nil"),
             Self::Callable(_arg0) => f.debug_tuple("Callable").finish(),
+            Self::Module(arg0) => f.debug_tuple("Module").field(&arg0.name).finish(),
         }
     }
 }
\ No newline at end of file
@@ -1,13 +1,21 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use serde_json::{json, Value};
+
 use crate::ast;
 use crate::ast::*;
+use crate::token::{Token, TokenLiteral, TokenType};
 
 use crate::impl_expr_visitable;
 use crate::impl_stmt_visitable;
 
+// Lisp-style form of the tree, one node per parenthesized list, as in the
+// book's appendix.
 pub struct AstPrinter;
 
 impl_expr_visitable! {
-    <String>, 
+    <String>,
     (Binary, binary),
     (Group, grouping),
     (Literal, literal),
@@ -16,10 +24,13 @@ impl_expr_visitable! {
     (Assign, assign),
     (Logical, logical),
     (Call, call),
+    (Update, update),
+    (Conditional, conditional),
+    (Get, get),
 }
 
 impl_stmt_visitable! {
-    <String>, 
+    <String>,
     (Expression, expression),
     (Print, print),
     (Var, var),
@@ -28,111 +39,139 @@ impl_stmt_visitable! {
     (While, while),
     (Function, function),
     (Return, return),
+    (Import, import),
+    (Export, export),
 }
 
 impl ExprVisitor<String> for AstPrinter {
     fn visit_binary(&self, expr: &Binary) -> String {
-        return self.parenthesize(
+        self.parenthesize(
             &expr.operator.lexeme,
             &[&expr.left, &expr.right],
-        );
+        )
     }
     fn visit_grouping(&self, expr: &Group) -> String {
-        return self.parenthesize("group", &[&expr.expression]);
+        self.parenthesize("group", &[&expr.expression])
     }
     fn visit_literal(&self, expr: &Literal) -> String {
-        expr.value.to_string()
+        match &expr.value {
+            TokenLiteral::String(s) => format!("{:?}", s),
+            value => value.to_string(),
+        }
     }
     fn visit_unary(&self, expr: &Unary) -> String {
-        return self.parenthesize(&expr.operator.lexeme, &[&expr.right]);
+        self.parenthesize(&expr.operator.lexeme, &[&expr.right])
     }
-    
+
     fn visit_variable(&self, expr: &Variable) -> String {
-        String::from(expr.name.lexeme.clone())
+        expr.name.lexeme.to_string()
     }
-    
+
     fn visit_assign(&self, stmt: &Assign) -> String {
-        let value = <ast::Expr as Clone>::clone(&stmt.value).accept(self);
-        String::from(format!("{} = {}", stmt.name.lexeme, value))
+        let value = self.print_expr(stmt.value.clone());
+        format!("(= {} {})", stmt.name.lexeme, value)
     }
-    
+
     fn visit_logical(&self, stmt: &Logical) -> String {
-        return self.parenthesize(&stmt.operator.lexeme, &[&stmt.left, &stmt.right]);
+        self.parenthesize(&stmt.operator.lexeme, &[&stmt.left, &stmt.right])
     }
-    
+
     fn visit_call(&self, stmt: &Call) -> String {
-        todo!()
+        let mut parts = vec![self.print_expr(stmt.callee.clone())];
+        parts.extend(stmt.arguments.iter().map(|arg| self.print_expr(arg.clone())));
+        self.list("call", parts)
+    }
+
+    // `(+= x 2)`, `(++ x)` before the name, `(x ++)` after it.
+    fn visit_update(&self, expr: &Update) -> String {
+        let (name, operator) = (&expr.name.lexeme, &expr.operator.lexeme);
+        match expr.operator._type {
+            TokenType::PLUSPLUS | TokenType::MINUSMINUS if expr.postfix => format!("({} {})", name, operator),
+            TokenType::PLUSPLUS | TokenType::MINUSMINUS => format!("({} {})", operator, name),
+            _ => format!("({} {} {})", operator, name, self.print_expr(expr.value.clone())),
+        }
+    }
+
+    fn visit_conditional(&self, expr: &Conditional) -> String {
+        self.parenthesize("?:", &[&expr.condition, &expr.then_branch, &expr.else_branch])
+    }
+
+    fn visit_get(&self, expr: &Get) -> String {
+        format!("(. {} {})", self.print_expr(expr.object.clone()), expr.name.lexeme)
     }
 }
 
 impl StmtVisitor<String> for AstPrinter {
     fn visit_expression(&self, stmt: &Expression) -> String {
-        let value = <ast::Expr as Clone>::clone(&stmt.expression).accept(self);
-        return format!("( {} )", value);
+        self.parenthesize(";", &[&stmt.expression])
     }
     fn visit_print(&self, stmt: &Print) -> String {
-        return self.parenthesize(&"print", &[&stmt.expression]);
+        self.parenthesize("print", &[&stmt.expression])
     }
     fn visit_var(&self, stmt: &Var) -> String {
         match &stmt.initializer {
             Some(expr) => {
-                let value = <ast::Expr as Clone>::clone(&expr).accept(self);
-                return format!("( var {} = {} )", stmt.name.lexeme, value);
+                let keyword = if stmt.constant { "const" } else { "var" };
+                format!("({} {} = {})", keyword, stmt.name.lexeme, self.print_expr(expr.clone()))
             },
             None => {
-                return format!("( var {} )", stmt.name.lexeme);
+                format!("(var {})", stmt.name.lexeme)
             },
         }
     }
     fn visit_block(&self, stmt: &Block) -> String {
-        let mut string_builder: Vec<String> = vec![];
-        string_builder.push("{".to_owned());
-        stmt.statements.iter().for_each(|stmt| {
-            string_builder.push("\n".to_owned());
-            let stmt = <ast::Stmt as Clone>::clone(&stmt).accept(self);
-            string_builder.push(stmt);
-        });
-        string_builder.push("\n}".to_owned());
-        return string_builder.join("");
+        let parts = stmt.statements.iter().map(|stmt| self.print_stmt(stmt.clone())).collect();
+        self.list("block", parts)
     }
-    
+
     fn visit_if(&self, stmt: &If) -> String {
-        let mut string_builder: Vec<String> = vec![];
-        string_builder.push("if ".to_owned());
-        string_builder.push(<ast::Expr as Clone>::clone(&stmt.condition).accept(self));
-        string_builder.push(" ( ".to_owned());
-        string_builder.push(<ast::Stmt as Clone>::clone(&stmt.then_branch).accept(self));
-        string_builder.push(" ) ".to_owned());
-        if let Some(else_branch) = &stmt.else_branch {
-            string_builder.push(" else ( ".to_owned());
-            string_builder.push(<ast::Stmt as Clone>::clone(&else_branch).accept(self));
-            string_builder.push(" ) ".to_owned());
+        let mut parts = vec![
+            self.print_expr(stmt.condition.clone()),
+            self.print_stmt(stmt.then_branch.clone()),
+        ];
+        match &stmt.else_branch {
+            Some(else_branch) => {
+                parts.push(self.print_stmt(else_branch.clone()));
+                self.list("if-else", parts)
+            }
+            None => self.list("if", parts),
         }
-        string_builder.push("\n".to_owned());
-        return string_builder.join("");
     }
-    
+
     fn visit_while(&self, stmt: &While) -> String {
-        let mut string_builder: Vec<String> = vec![];
-        string_builder.push("( while ".to_owned());
-        string_builder.push(<ast::Expr as Clone>::clone(&stmt.condition).accept(self));
-        string_builder.push(" (".to_owned());
-        string_builder.push(<ast::Stmt as Clone>::clone(&stmt.body).accept(self));
-        string_builder.push(")\n".to_owned());
-        return string_builder.join("");
-    }
-    
+        let parts = vec![self.print_expr(stmt.condition.clone()), self.print_stmt(stmt.body.clone())];
+        self.list("while", parts)
+    }
+
     fn visit_function(&self, stmt: &Function) -> String {
-        todo!()
+        let params: Vec<&str> = stmt.params.iter().map(|param| param.lexeme.as_ref()).collect();
+        let head = format!("fun {}({})", stmt.name.lexeme, params.join(" "));
+        // The body is always a block; its statements are listed directly.
+        let parts = match stmt.body.as_ref() {
+            Stmt::Block(body) => body.statements.iter().map(|stmt| self.print_stmt(stmt.clone())).collect(),
+            body => vec![self.print_stmt(Rc::new(body.clone()))],
+        };
+        self.list(&head, parts)
     }
-    
+
     fn visit_return(&self, stmt: &Return) -> String {
-        todo!()
+        match &stmt.value {
+            Some(value) => self.parenthesize("return", &[value]),
+            None => "(return)".to_owned(),
+        }
+    }
+
+    fn visit_import(&self, stmt: &Import) -> String {
+        format!("(import {} as {})", stmt.path.lexeme, stmt.name.lexeme)
+    }
+
+    fn visit_export(&self, stmt: &Export) -> String {
+        self.list("export", vec![self.print_stmt(stmt.declaration.clone())])
     }
 }
 
-impl AstPrinter 
-where Self: ExprVisitor<String> 
+impl AstPrinter
+where Self: ExprVisitor<String>
 {
     pub fn new() -> Self {
         AstPrinter {}
@@ -149,12 +188,278 @@ where Self: ExprVisitor<String>
         string_builder.push(name.to_owned());
         exprs.iter().for_each(|expr| {
             string_builder.push(" ".to_owned());
-            let expr = <ast::Expr as Clone>::clone(&expr).accept(self);
+            let expr = <ast::Expr as Clone>::clone(expr).accept(self);
             string_builder.push(expr);
         });
         string_builder.push(")".to_owned());
-        return string_builder.join("");
+        string_builder.join("")
+    }
+    fn list(&self, name: &str, parts: Vec<String>) -> String {
+        let mut string_builder = vec![name.to_owned()];
+        string_builder.extend(parts);
+        format!("({})", string_builder.join(" "))
     }
 }
 
+// JSON form of the tree for tooling. Every node has a `kind` and a `span`
+// covering the tokens it was parsed from, `{ line, start, end }` in chars,
+// or null when it has none (a literal or block the parser added). Variables
+// and assignments also get the `depth` the resolver bound them at, null
+// for globals.
+pub struct AstJson<'a> {
+    locals: &'a HashMap<RcExpr, usize>,
+}
 
+impl_expr_visitable! {
+    <Value>,
+    (Binary, binary),
+    (Group, grouping),
+    (Literal, literal),
+    (Unary, unary),
+    (Variable, variable),
+    (Assign, assign),
+    (Logical, logical),
+    (Call, call),
+    (Update, update),
+    (Conditional, conditional),
+    (Get, get),
+}
+
+impl_stmt_visitable! {
+    <Value>,
+    (Expression, expression),
+    (Print, print),
+    (Var, var),
+    (Block, block),
+    (If, if),
+    (While, while),
+    (Function, function),
+    (Return, return),
+    (Import, import),
+    (Export, export),
+}
+
+// The smallest span holding `tokens` and the spans of `children`.
+fn span(tokens: &[&Token], children: &[&Value]) -> Value {
+    let mut parts: Vec<(usize, usize, usize)> =
+        tokens.iter().map(|token| (token.start(), token.offset, token.line)).collect();
+    for child in children {
+        let span = &child["span"];
+        if let (Some(start), Some(end), Some(line)) = (span["start"].as_u64(), span["end"].as_u64(), span["line"].as_u64()) {
+            parts.push((start as usize, end as usize, line as usize));
+        }
+    }
+    let Some(&(start, _, line)) = parts.iter().min() else {
+        return Value::Null;
+    };
+    let end = parts.iter().map(|part| part.1).max().unwrap();
+    json!({ "line": line, "start": start, "end": end })
+}
+
+fn node(kind: &str, tokens: &[&Token], children: &[&Value], mut fields: Value) -> Value {
+    fields["kind"] = json!(kind);
+    fields["span"] = span(tokens, children);
+    fields
+}
+
+impl ExprVisitor<Value> for AstJson<'_> {
+    fn visit_binary(&self, expr: &Binary) -> Value {
+        let (left, right) = (self.print_expr(expr.left.clone()), self.print_expr(expr.right.clone()));
+        node("Binary", &[&expr.operator], &[&left, &right], json!({ "operator": expr.operator.lexeme.as_ref(), "left": left, "right": right }))
+    }
+    fn visit_grouping(&self, expr: &Group) -> Value {
+        let expression = self.print_expr(expr.expression.clone());
+        node("Group", &[], &[&expression], json!({ "expression": expression }))
+    }
+    fn visit_literal(&self, expr: &Literal) -> Value {
+        let value = match &expr.value {
+            TokenLiteral::Int(n) => json!(n),
+            TokenLiteral::Number(n) => json!(n),
+            TokenLiteral::String(s) => json!(s.as_ref()),
+            TokenLiteral::Bool(b) => json!(b),
+            TokenLiteral::Nil => Value::Null,
+        };
+        let tokens: Vec<&Token> = expr.token.iter().collect();
+        node("Literal", &tokens, &[], json!({ "value": value }))
+    }
+    fn visit_unary(&self, expr: &Unary) -> Value {
+        let right = self.print_expr(expr.right.clone());
+        node("Unary", &[&expr.operator], &[&right], json!({ "operator": expr.operator.lexeme.as_ref(), "right": right }))
+    }
+    fn visit_variable(&self, expr: &Variable) -> Value {
+        let depth = self.depth(Expr::Variable(expr.clone()));
+        node("Variable", &[&expr.name], &[], json!({ "name": expr.name.lexeme.as_ref(), "depth": depth }))
+    }
+    fn visit_assign(&self, expr: &Assign) -> Value {
+        let value = self.print_expr(expr.value.clone());
+        let depth = self.depth(Expr::Assign(expr.clone()));
+        node("Assign", &[&expr.name], &[&value], json!({ "name": expr.name.lexeme.as_ref(), "value": value, "depth": depth }))
+    }
+    fn visit_logical(&self, expr: &Logical) -> Value {
+        let (left, right) = (self.print_expr(expr.left.clone()), self.print_expr(expr.right.clone()));
+        node("Logical", &[&expr.operator], &[&left, &right], json!({ "operator": expr.operator.lexeme.as_ref(), "left": left, "right": right }))
+    }
+    fn visit_call(&self, expr: &Call) -> Value {
+        let callee = self.print_expr(expr.callee.clone());
+        let arguments: Vec<Value> = expr.arguments.iter().map(|arg| self.print_expr(arg.clone())).collect();
+        let mut children: Vec<&Value> = arguments.iter().collect();
+        children.push(&callee);
+        let span = span(&[&expr.paren], &children);
+        json!({ "kind": "Call", "span": span, "callee": callee, "arguments": arguments })
+    }
+    fn visit_update(&self, expr: &Update) -> Value {
+        let value = self.print_expr(expr.value.clone());
+        let depth = self.depth(Expr::Update(expr.clone()));
+        node(
+            "Update",
+            &[&expr.name, &expr.operator],
+            &[&value],
+            json!({ "name": expr.name.lexeme.as_ref(), "operator": expr.operator.lexeme.as_ref(), "value": value, "postfix": expr.postfix, "depth": depth }),
+        )
+    }
+    fn visit_conditional(&self, expr: &Conditional) -> Value {
+        let condition = self.print_expr(expr.condition.clone());
+        let then_branch = self.print_expr(expr.then_branch.clone());
+        let else_branch = self.print_expr(expr.else_branch.clone());
+        node(
+            "Conditional",
+            &[&expr.question],
+            &[&condition, &then_branch, &else_branch],
+            json!({ "condition": condition, "then": then_branch, "else": else_branch }),
+        )
+    }
+    fn visit_get(&self, expr: &Get) -> Value {
+        let object = self.print_expr(expr.object.clone());
+        node("Get", &[&expr.name], &[&object], json!({ "object": object, "name": expr.name.lexeme.as_ref() }))
+    }
+}
+
+impl StmtVisitor<Value> for AstJson<'_> {
+    fn visit_expression(&self, stmt: &Expression) -> Value {
+        let expression = self.print_expr(stmt.expression.clone());
+        node("Expression", &[], &[&expression], json!({ "expression": expression }))
+    }
+    fn visit_print(&self, stmt: &Print) -> Value {
+        let expression = self.print_expr(stmt.expression.clone());
+        node("Print", &[&stmt.keyword], &[&expression], json!({ "expression": expression }))
+    }
+    fn visit_var(&self, stmt: &Var) -> Value {
+        let initializer = stmt.initializer.as_ref().map_or(Value::Null, |expr| self.print_expr(expr.clone()));
+        node("Var", &[&stmt.name], &[&initializer], json!({ "name": stmt.name.lexeme.as_ref(), "initializer": initializer, "constant": stmt.constant, "doc": stmt.doc.as_deref() }))
+    }
+    fn visit_block(&self, stmt: &Block) -> Value {
+        let statements: Vec<Value> = stmt.statements.iter().map(|stmt| self.print_stmt(stmt.clone())).collect();
+        let children: Vec<&Value> = statements.iter().collect();
+        let tokens: Vec<&Token> = stmt.brace.iter().collect();
+        let span = span(&tokens, &children);
+        json!({ "kind": "Block", "span": span, "statements": statements })
+    }
+    fn visit_if(&self, stmt: &If) -> Value {
+        let condition = self.print_expr(stmt.condition.clone());
+        let then_branch = self.print_stmt(stmt.then_branch.clone());
+        let else_branch = stmt.else_branch.as_ref().map_or(Value::Null, |branch| self.print_stmt(branch.clone()));
+        node(
+            "If",
+            &[&stmt.keyword],
+            &[&condition, &then_branch, &else_branch],
+            json!({ "condition": condition, "then": then_branch, "else": else_branch }),
+        )
+    }
+    fn visit_while(&self, stmt: &While) -> Value {
+        let condition = self.print_expr(stmt.condition.clone());
+        let body = self.print_stmt(stmt.body.clone());
+        node("While", &[&stmt.keyword], &[&condition, &body], json!({ "condition": condition, "body": body }))
+    }
+    fn visit_function(&self, stmt: &Function) -> Value {
+        let params: Vec<&str> = stmt.params.iter().map(|param| param.lexeme.as_ref()).collect();
+        let body = self.print_stmt(stmt.body.clone());
+        node("Function", &[&stmt.name], &[&body], json!({ "name": stmt.name.lexeme.as_ref(), "params": params, "body": body, "doc": stmt.doc.as_deref() }))
+    }
+    fn visit_return(&self, stmt: &Return) -> Value {
+        let value = stmt.value.as_ref().map_or(Value::Null, |expr| self.print_expr(expr.clone()));
+        node("Return", &[&stmt.keyword], &[&value], json!({ "value": value }))
+    }
+    fn visit_import(&self, stmt: &Import) -> Value {
+        node(
+            "Import",
+            &[&stmt.keyword, &stmt.path, &stmt.name],
+            &[],
+            json!({ "path": stmt.path.literal.to_string(), "name": stmt.name.lexeme.as_ref() }),
+        )
+    }
+    fn visit_export(&self, stmt: &Export) -> Value {
+        let declaration = self.print_stmt(stmt.declaration.clone());
+        node("Export", &[&stmt.keyword], &[&declaration], json!({ "declaration": declaration }))
+    }
+}
+
+impl<'a> AstJson<'a> {
+    // `locals` is the interpreter's table of resolved expressions.
+    pub fn new(locals: &'a HashMap<RcExpr, usize>) -> Self {
+        AstJson { locals }
+    }
+    pub fn print_expr(&self, expr: RcExpr) -> Value {
+        <ast::Expr as Clone>::clone(&expr).accept(self)
+    }
+    pub fn print_stmt(&self, stmt: RcStmt) -> Value {
+        <ast::Stmt as Clone>::clone(&stmt).accept(self)
+    }
+    fn depth(&self, expr: Expr) -> Option<usize> {
+        self.locals.get(&Rc::new(expr)).copied()
+    }
+}
+
+#[test]
+fn test_print_program() {
+    use crate::{AstFormat, Lox};
+
+    let source = "var n = 1;\nfun f(a, b) {\n  if (a > -b) return f(a, b - 1);\n  else n = !true;\n  while (a) { print (a and \"s\"); }\n  return;\n}\n";
+    let mut lox = Lox::new();
+    let sexpr = lox.dump_ast(source, AstFormat::SExpr).unwrap();
+    assert_eq!(
+        sexpr,
+        "(var n = 1)\n(fun f(a b) (if-else (> a (- b)) (return (call f a (- b 1))) (; (= n (! true)))) (while a (block (print (group (and a \"s\"))))) (return))\n"
+    );
+
+    let json: Value = serde_json::from_str(&lox.dump_ast(source, AstFormat::Json).unwrap()).unwrap();
+    let function = &json[1];
+    assert_eq!(function["kind"], "Function");
+    assert_eq!(function["params"], json!(["a", "b"]));
+    assert_eq!(function["span"], json!({ "line": 2, "start": 15, "end": 120 }));
+    let condition = &function["body"]["statements"][0]["condition"];
+    assert_eq!(condition["kind"], "Binary");
+    assert_eq!(condition["span"], json!({ "line": 3, "start": 31, "end": 37 }));
+    // `a` is a parameter one scope out from the body block; `n` is global.
+    assert_eq!(condition["left"]["depth"], 1);
+    assert_eq!(function["body"]["statements"][0]["else"]["expression"]["depth"], Value::Null);
+    assert_eq!(json[0]["initializer"], json!({ "kind": "Literal", "span": { "line": 1, "start": 8, "end": 9 }, "value": 1 }));
+    // A block starts at its opening brace.
+    assert_eq!(function["body"]["span"], json!({ "line": 2, "start": 23, "end": 120 }));
+}
+
+#[test]
+fn test_print_operator_precedence() {
+    use crate::{AstFormat, Lox};
+
+    let source = "x & 1 == 0 | y ^ z;\n1 << 2 + 3 ** 2 ** -1 * ~4;\n-2 ** 2;\n";
+    let sexpr = Lox::new().dump_ast(source, AstFormat::SExpr).unwrap();
+    assert_eq!(
+        sexpr,
+        "(; (== (& x 1) (| 0 (^ y z))))\n(; (<< 1 (+ 2 (* (** 3 (** 2 (- 1))) (~ 4)))))\n(; (- (** 2 2)))\n"
+    );
+
+    let source = "var x = a or b ? c : d ? e : f;\nx += y = 2;\n-x++ * ++x;\n";
+    let sexpr = Lox::new().dump_ast(source, AstFormat::SExpr).unwrap();
+    assert_eq!(sexpr, "(var x = (?: (or a b) c (?: d e f)))\n(; (+= x (= y 2)))\n(; (* (- (x ++)) (++ x)))\n");
+}
+
+#[test]
+fn test_doc_comments() {
+    use crate::{AstFormat, Lox};
+
+    let source = "/// The answer.\nvar a = 42;\n/// Not attached.\nprint a;\n{\n  /// Doubles `x`.\n  ///\n  ///   Indented.\n  fun f(x) { return x * 2; }\n}\n";
+    let json: Value = serde_json::from_str(&Lox::new().dump_ast(source, AstFormat::Json).unwrap()).unwrap();
+    assert_eq!(json[0]["doc"], "The answer.");
+    assert_eq!(json[1]["kind"], "Print");
+    assert_eq!(json[2]["statements"][0]["doc"], "Doubles `x`.\n\n  Indented.");
+}
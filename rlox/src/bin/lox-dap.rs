@@ -0,0 +1,11 @@
+use std::io::{stdin, stdout, BufReader};
+use std::rc::Rc;
+
+use jlox_rust::dap::Session;
+
+// Debug Adapter Protocol server on stdin/stdout. Script output is sent to
+// the client as "output" events, so nothing else may write to stdout.
+fn main() {
+    let session = Rc::new(Session::new(Box::new(BufReader::new(stdin())), Box::new(stdout())));
+    session.run();
+}
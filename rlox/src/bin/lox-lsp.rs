@@ -0,0 +1,13 @@
+use std::io::{stdin, stdout, BufReader};
+use std::process::exit;
+
+use jlox_rust::lsp::Server;
+
+// Language Server Protocol server on stdin/stdout. As the protocol asks,
+// exits with 1 unless the client sent `shutdown` before `exit`.
+fn main() {
+    let mut server = Server::new(Box::new(BufReader::new(stdin())), Box::new(stdout()));
+    if !server.run() {
+        exit(1);
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+
+use crate::ast::{RcStmt, Stmt};
+
+// Hit counts for one script, keyed by line. Every line that starts a
+// statement is present, so lines that never ran show up with zero hits.
+pub struct FileCoverage {
+    pub path: String,
+    pub source: String,
+    pub lines: BTreeMap<usize, usize>,
+}
+
+impl FileCoverage {
+    pub fn lines_hit(&self) -> usize {
+        self.lines.values().filter(|hits| **hits > 0).count()
+    }
+
+    pub fn percent(&self) -> f64 {
+        if self.lines.is_empty() {
+            return 100.0;
+        }
+        100.0 * self.lines_hit() as f64 / self.lines.len() as f64
+    }
+}
+
+// Statement coverage over every script run while it is enabled. The
+// interpreter calls `hit` for each statement it executes.
+#[derive(Default)]
+pub struct Coverage {
+    pub files: Vec<FileCoverage>,
+    // Index into `files` by the file id on tokens. A script run again under
+    // the same id starts a new entry.
+    by_id: HashMap<usize, usize>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Starts attributing hits in file `id` to `path`, with every statement
+    // line in `stmts` marked executable.
+    pub fn begin_file(&mut self, id: usize, path: &str, source: &str, stmts: &[RcStmt]) {
+        let mut lines = BTreeMap::new();
+        add_lines(stmts, &mut lines);
+        self.by_id.insert(id, self.files.len());
+        self.files.push(FileCoverage { path: path.to_string(), source: source.to_string(), lines });
+    }
+
+    pub fn hit(&mut self, id: usize, line: usize) {
+        if let Some(&index) = self.by_id.get(&id) {
+            *self.files[index].lines.entry(line).or_default() += 1;
+        }
+    }
+
+    // The report in LCOV's tracefile format, as read by genhtml and most
+    // CI coverage services.
+    pub fn lcov(&self) -> String {
+        let mut out = String::new();
+        for file in self.files.iter() {
+            out.push_str("TN:\n");
+            out.push_str(&format!("SF:{}\n", file.path));
+            for (line, hits) in file.lines.iter() {
+                out.push_str(&format!("DA:{},{}\n", line, hits));
+            }
+            out.push_str(&format!("LF:{}\n", file.lines.len()));
+            out.push_str(&format!("LH:{}\n", file.lines_hit()));
+            out.push_str("end_of_record\n");
+        }
+        out
+    }
+}
+
+fn add_lines(stmts: &[RcStmt], lines: &mut BTreeMap<usize, usize>) {
+    for stmt in stmts {
+        add_stmt_lines(stmt, lines);
+    }
+}
+
+fn add_stmt_lines(stmt: &RcStmt, lines: &mut BTreeMap<usize, usize>) {
+    if let Some(line) = stmt.line() {
+        lines.entry(line).or_insert(0);
+    }
+    match stmt.as_ref() {
+        Stmt::Block(block) => add_lines(&block.statements, lines),
+        Stmt::If(stmt) => {
+            add_stmt_lines(&stmt.then_branch, lines);
+            if let Some(else_branch) = &stmt.else_branch {
+                add_stmt_lines(else_branch, lines);
+            }
+        }
+        Stmt::While(stmt) => add_stmt_lines(&stmt.body, lines),
+        Stmt::Function(stmt) => add_stmt_lines(&stmt.body, lines),
+        Stmt::Export(stmt) => add_stmt_lines(&stmt.declaration, lines),
+        _ => {}
+    }
+}
+
+// Annotated source, gcov style: hit counts in the margin and "#####" on
+// executable lines that never ran.
+impl Display for Coverage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, file) in self.files.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}: {:.1}% of {} lines", file.path, file.percent(), file.lines.len())?;
+            for (index, text) in file.source.lines().enumerate() {
+                let margin = match file.lines.get(&(index + 1)) {
+                    Some(0) => "#####".to_string(),
+                    Some(hits) => hits.to_string(),
+                    None => "-".to_string(),
+                };
+                write!(f, "\n{:>9}: {:>4}: {}", margin, index + 1, text)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,409 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+
+use serde_json::{json, Value};
+
+use crate::debugger::{self, Breakpoint, DebugHook, DebugState, StepMode, StopReason};
+use crate::framing;
+use crate::errors::{InterruptKind, RuntimeInterrupt};
+use crate::interpreter::Interpreter;
+use crate::Lox;
+
+// Debug Adapter Protocol over a pair of streams, for `lox-dap`. Scripts run
+// on the same thread as the message loop: while paused, the debug hook
+// keeps reading requests until one resumes execution.
+
+// Lox has a single thread of execution.
+const THREAD_ID: i64 = 1;
+// `variablesReference` of the global scope; the locals of frame `n` use
+// `n + FRAME_REFERENCES`.
+const GLOBALS_REFERENCE: i64 = 1;
+const FRAME_REFERENCES: i64 = 2;
+
+enum Action {
+    Stay,
+    Resume(StepMode),
+    Disconnect,
+}
+
+struct Launch {
+    program: String,
+    stop_on_entry: bool,
+}
+
+pub struct Session {
+    input: RefCell<Box<dyn BufRead>>,
+    output: Rc<RefCell<Box<dyn Write>>>,
+    seq: Rc<Cell<i64>>,
+    state: RefCell<DebugState>,
+    // Set while an `evaluate` runs, so calls it makes don't stop again.
+    evaluating: Cell<bool>,
+    disconnected: Cell<bool>,
+}
+
+fn write_message(output: &RefCell<Box<dyn Write>>, seq: &Cell<i64>, mut message: Value) {
+    seq.set(seq.get() + 1);
+    message["seq"] = json!(seq.get());
+    framing::write_message(&mut **output.borrow_mut(), &message);
+}
+
+impl Session {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Self {
+            input: RefCell::new(input),
+            output: Rc::new(RefCell::new(output)),
+            seq: Rc::new(Cell::new(0)),
+            state: RefCell::new(DebugState::new("", StepMode::Continue)),
+            evaluating: Cell::new(false),
+            disconnected: Cell::new(false),
+        }
+    }
+
+    // Next request from the client, or `None` once its stream ends.
+    fn read_message(&self) -> Option<Value> {
+        framing::read_message(&mut **self.input.borrow_mut())
+    }
+
+    fn send(&self, message: Value) {
+        write_message(&self.output, &self.seq, message);
+    }
+
+    fn respond(&self, request: &Value, body: Value) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": true,
+            "command": request["command"],
+            "body": body,
+        }));
+    }
+
+    fn fail(&self, request: &Value, message: &str) {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": false,
+            "command": request["command"],
+            "message": message,
+        }));
+    }
+
+    fn event(&self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    // Serves requests until the client disconnects or its stream ends.
+    pub fn run(self: &Rc<Self>) {
+        let mut launch = None;
+        let mut configured = false;
+        let mut launched = false;
+        while let Some(request) = self.read_message() {
+            match request["command"].as_str().unwrap_or("") {
+                "initialize" => {
+                    self.respond(&request, json!({
+                        "supportsConfigurationDoneRequest": true,
+                        "supportsEvaluateForHovers": true,
+                    }));
+                    self.event("initialized", json!({}));
+                }
+                "launch" => {
+                    let Some(program) = request["arguments"]["program"].as_str() else {
+                        self.fail(&request, "Missing 'program'.");
+                        continue;
+                    };
+                    launch = Some(Launch {
+                        program: program.to_string(),
+                        stop_on_entry: request["arguments"]["stopOnEntry"].as_bool().unwrap_or(false),
+                    });
+                    self.respond(&request, json!({}));
+                }
+                "configurationDone" => {
+                    configured = true;
+                    self.respond(&request, json!({}));
+                }
+                _ => match self.handle(&request, None) {
+                    Action::Disconnect => break,
+                    Action::Stay | Action::Resume(_) => {}
+                },
+            }
+            if let (false, true, Some(launch)) = (launched, configured, &launch) {
+                launched = true;
+                self.launch(launch);
+                if self.disconnected.get() {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn launch(self: &Rc<Self>, launch: &Launch) {
+        let source = match std::fs::read_to_string(&launch.program) {
+            Ok(source) => source,
+            Err(err) => {
+                self.output_event("stderr", &format!("Could not read {}: {}\n", launch.program, err));
+                self.event("terminated", json!({}));
+                return;
+            }
+        };
+        {
+            let mut state = self.state.borrow_mut();
+            state.path = launch.program.clone();
+            state.mode = if launch.stop_on_entry { StepMode::Entry } else { StepMode::Continue };
+        }
+
+        let mut lox = Lox::new();
+        let (output, seq) = (self.output.clone(), self.seq.clone());
+        lox.interpreter().set_print_handler(Some(Box::new(move |text: &str| {
+            let body = json!({ "category": "stdout", "output": format!("{}\n", text) });
+            write_message(&output, &seq, json!({ "type": "event", "event": "output", "body": body }));
+        })));
+        lox.interpreter().set_debug_hook(Some(self.clone()));
+        lox.interpreter().set_script_path(std::path::Path::new(&launch.program));
+        lox.run_named(&launch.program, &source);
+
+        if let (Some(message), false) = (&lox.last_error, self.disconnected.get()) {
+            self.output_event("stderr", &format!("{}\n", message));
+        }
+        let exit_code = if lox.had_error {
+            65
+        } else if lox.had_runtime_error {
+            70
+        } else {
+            0
+        };
+        self.event("exited", json!({ "exitCode": exit_code }));
+        self.event("terminated", json!({}));
+    }
+
+    fn output_event(&self, category: &str, output: &str) {
+        self.event("output", json!({ "category": category, "output": output }));
+    }
+
+    // Requests that don't start a script. `paused` is set while stopped at
+    // a statement, with the file and line it is on.
+    fn handle(&self, request: &Value, paused: Option<(&Interpreter, usize, usize)>) -> Action {
+        let arguments = &request["arguments"];
+        let command = request["command"].as_str().unwrap_or("");
+        match command {
+            "setBreakpoints" => {
+                let path = arguments["source"]["path"].as_str().unwrap_or("").to_string();
+                let lines: Vec<usize> = arguments["breakpoints"]
+                    .as_array()
+                    .map(|breakpoints| breakpoints.iter().filter_map(|b| b["line"].as_u64()).map(|line| line as usize).collect())
+                    .unwrap_or_default();
+                let mut state = self.state.borrow_mut();
+                state.breakpoints.retain(|breakpoint| breakpoint.file.as_deref() != Some(path.as_str()));
+                for line in lines.iter() {
+                    state.breakpoints.push(Breakpoint { file: Some(path.clone()), line: *line });
+                }
+                let verified: Vec<Value> = lines.iter().map(|line| json!({ "verified": true, "line": line })).collect();
+                self.respond(request, json!({ "breakpoints": verified }));
+            }
+            "threads" => {
+                self.respond(request, json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }));
+            }
+            "disconnect" | "terminate" => {
+                self.disconnected.set(true);
+                self.respond(request, json!({}));
+                return Action::Disconnect;
+            }
+            "stackTrace" | "scopes" | "variables" | "evaluate" | "continue" | "next" | "stepIn" | "stepOut" => {
+                let Some((interpreter, file, line)) = paused else {
+                    self.fail(request, "The script is not paused.");
+                    return Action::Stay;
+                };
+                return self.handle_paused(request, command, interpreter, file, line);
+            }
+            _ => self.fail(request, &format!("Unsupported request '{}'.", command)),
+        }
+        Action::Stay
+    }
+
+    fn handle_paused(&self, request: &Value, command: &str, interpreter: &Interpreter, file: usize, line: usize) -> Action {
+        let arguments = &request["arguments"];
+        let depth = interpreter.call_stack().len();
+        match command {
+            "stackTrace" => {
+                let frames = self.stack_frames(interpreter, file, line);
+                self.respond(request, json!({ "totalFrames": frames.len(), "stackFrames": frames }));
+            }
+            "scopes" => {
+                let frame = arguments["frameId"].as_i64().unwrap_or(0);
+                self.respond(request, json!({ "scopes": [
+                    { "name": "Locals", "variablesReference": frame + FRAME_REFERENCES, "expensive": false },
+                    { "name": "Globals", "variablesReference": GLOBALS_REFERENCE, "expensive": false },
+                ]}));
+            }
+            "variables" => {
+                let reference = arguments["variablesReference"].as_i64().unwrap_or(0);
+                let variables = self.variables(interpreter, reference);
+                self.respond(request, json!({ "variables": variables }));
+            }
+            "evaluate" => {
+                let expression = arguments["expression"].as_str().unwrap_or("");
+                let scope = arguments["frameId"].as_i64().and_then(|frame| frame_scope(interpreter, frame));
+                self.evaluating.set(true);
+                let result = debugger::evaluate(interpreter, expression, scope);
+                self.evaluating.set(false);
+                match result {
+                    Ok(value) => self.respond(request, json!({ "result": debugger::describe(&value), "variablesReference": 0 })),
+                    Err(message) => self.fail(request, &message),
+                }
+            }
+            _ => {
+                let mode = match command {
+                    "next" => StepMode::StepOver(depth),
+                    "stepIn" => StepMode::StepIn,
+                    "stepOut" => StepMode::StepOut(depth),
+                    _ => StepMode::Continue,
+                };
+                self.respond(request, json!({ "allThreadsContinued": true }));
+                return Action::Resume(mode);
+            }
+        }
+        Action::Stay
+    }
+
+    // Innermost first; frame ids count up from 0 at the innermost frame.
+    // Each frame's source is the file its current statement is in.
+    fn stack_frames(&self, interpreter: &Interpreter, file: usize, line: usize) -> Vec<Value> {
+        let source = |file: usize| {
+            let path = self.state.borrow().file_path(interpreter, file);
+            let name = std::path::Path::new(&path).file_name().map_or(path.clone(), |name| name.to_string_lossy().to_string());
+            json!({ "name": name, "path": path })
+        };
+        let mut frames = vec![];
+        let (mut file, mut line) = (file, line);
+        for frame in interpreter.call_stack().iter().rev() {
+            frames.push(json!({ "id": frames.len(), "name": frame.function, "line": line, "column": 1, "source": source(file) }));
+            (file, line) = (frame.call_site.file, frame.call_site.line);
+        }
+        frames.push(json!({ "id": frames.len(), "name": "script", "line": line, "column": 1, "source": source(file) }));
+        frames
+    }
+
+    fn variables(&self, interpreter: &Interpreter, reference: i64) -> Vec<Value> {
+        let env = interpreter.environment.borrow();
+        let scopes = if reference == GLOBALS_REFERENCE {
+            vec![0]
+        } else {
+            let Some(scope) = frame_scope(interpreter, reference - FRAME_REFERENCES) else {
+                return vec![];
+            };
+            env.scope_chain(scope).into_iter().filter(|scope| !env.is_global(*scope)).collect()
+        };
+        // Inner bindings shadow outer ones of the same name.
+        let mut seen = std::collections::HashSet::new();
+        let mut variables = vec![];
+        for scope in scopes {
+            for (name, value) in env.bindings(scope) {
+                if seen.insert(name) {
+                    variables.push(json!({ "name": name.to_string(), "value": debugger::describe(&value), "variablesReference": 0 }));
+                }
+            }
+        }
+        variables
+    }
+}
+
+fn frame_scope(interpreter: &Interpreter, frame: i64) -> Option<usize> {
+    let frame = usize::try_from(frame).ok()?;
+    interpreter.environment.borrow().frame_scopes().get(frame).copied()
+}
+
+impl DebugHook for Session {
+    fn before_statement(&self, interpreter: &Interpreter, file: usize, line: usize) -> Result<(), Box<dyn Any>> {
+        if self.evaluating.get() {
+            return Ok(());
+        }
+        let depth = interpreter.call_stack().len();
+        let path = self.state.borrow().file_path(interpreter, file);
+        let Some(reason) = self.state.borrow_mut().stop_reason(file, &path, line, depth) else {
+            return Ok(());
+        };
+        let reason = match reason {
+            StopReason::Entry => "entry",
+            StopReason::Step => "step",
+            StopReason::Breakpoint => "breakpoint",
+        };
+        self.event("stopped", json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true }));
+        loop {
+            let Some(request) = self.read_message() else {
+                self.disconnected.set(true);
+                return Err(RuntimeInterrupt::new(InterruptKind::Cancelled).into());
+            };
+            match self.handle(&request, Some((interpreter, file, line))) {
+                Action::Stay => {}
+                Action::Resume(mode) => {
+                    self.state.borrow_mut().mode = mode;
+                    return Ok(());
+                }
+                Action::Disconnect => return Err(RuntimeInterrupt::new(InterruptKind::Cancelled).into()),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_dap_session() {
+    let path = std::env::temp_dir().join(format!("lox-dap-{}.lox", std::process::id()));
+    let path = path.to_string_lossy().to_string();
+    std::fs::write(&path, "fun add(a, b) {\n  var sum = a + b;\n  return sum;\n}\nprint add(1, 2);\n").unwrap();
+
+    let requests = [
+        json!({ "seq": 1, "type": "request", "command": "initialize", "arguments": {} }),
+        json!({ "seq": 2, "type": "request", "command": "launch", "arguments": { "program": path } }),
+        json!({ "seq": 3, "type": "request", "command": "setBreakpoints", "arguments": { "source": { "path": path }, "breakpoints": [{ "line": 3 }] } }),
+        json!({ "seq": 4, "type": "request", "command": "configurationDone" }),
+        json!({ "seq": 5, "type": "request", "command": "stackTrace", "arguments": { "threadId": 1 } }),
+        json!({ "seq": 6, "type": "request", "command": "scopes", "arguments": { "frameId": 0 } }),
+        json!({ "seq": 7, "type": "request", "command": "variables", "arguments": { "variablesReference": 2 } }),
+        json!({ "seq": 8, "type": "request", "command": "variables", "arguments": { "variablesReference": 1 } }),
+        json!({ "seq": 9, "type": "request", "command": "evaluate", "arguments": { "expression": "sum * 10", "frameId": 0 } }),
+        json!({ "seq": 10, "type": "request", "command": "continue", "arguments": { "threadId": 1 } }),
+        json!({ "seq": 11, "type": "request", "command": "disconnect" }),
+    ];
+    let input: String = requests.into_iter().map(framing::frame).collect();
+    let output = Rc::new(RefCell::new(vec![]));
+    let output_stream = Box::new(debugger::SharedOutput(output.clone()));
+    let session = Rc::new(Session::new(Box::new(std::io::Cursor::new(input)), output_stream));
+    session.run();
+    std::fs::remove_file(&path).unwrap();
+
+    let output = String::from_utf8(output.borrow().clone()).unwrap();
+    let messages: Vec<Value> = output
+        .split("Content-Length: ")
+        .filter(|chunk| !chunk.is_empty())
+        .map(|chunk| serde_json::from_str(chunk.split_once("\r\n\r\n").unwrap().1).unwrap())
+        .collect();
+    let find = |predicate: &dyn Fn(&Value) -> bool| messages.iter().find(|message| predicate(message)).unwrap().clone();
+
+    let stopped = find(&|m| m["event"] == "stopped");
+    assert_eq!(stopped["body"]["reason"], "breakpoint");
+    let trace = find(&|m| m["command"] == "stackTrace");
+    assert_eq!(trace["body"]["stackFrames"][0]["name"], "add");
+    assert_eq!(trace["body"]["stackFrames"][0]["line"], 3);
+    assert_eq!(trace["body"]["stackFrames"][1]["name"], "script");
+    assert_eq!(trace["body"]["stackFrames"][1]["line"], 5);
+    let scopes = find(&|m| m["command"] == "scopes");
+    assert_eq!(scopes["body"]["scopes"][0]["name"], "Locals");
+    assert_eq!(scopes["body"]["scopes"][0]["variablesReference"], 2);
+    assert_eq!(scopes["body"]["scopes"][1]["variablesReference"], 1);
+    let names = |seq: i64| -> Vec<String> {
+        let variables = find(&|m| m["command"] == "variables" && m["request_seq"] == seq);
+        variables["body"]["variables"].as_array().unwrap().iter().map(|v| v["name"].as_str().unwrap().to_string()).collect()
+    };
+    let mut locals = names(7);
+    locals.sort();
+    assert_eq!(locals, ["a", "b", "sum"]);
+    assert!(names(8).contains(&"add".to_string()));
+    let evaluated = find(&|m| m["command"] == "evaluate");
+    assert_eq!(evaluated["body"]["result"], "30");
+    let printed = find(&|m| m["event"] == "output");
+    assert_eq!(printed["body"]["output"], "3\n");
+    let exited = find(&|m| m["event"] == "exited");
+    assert_eq!(exited["body"]["exitCode"], 0);
+    assert!(messages.iter().any(|m| m["command"] == "disconnect" && m["success"] == true));
+}
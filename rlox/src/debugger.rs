@@ -0,0 +1,427 @@
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::io::{BufRead, Write};
+
+use crate::ast::LoxValue;
+use crate::errors::{InterruptKind, RuntimeError, RuntimeInterrupt};
+use crate::interpreter::Interpreter;
+use crate::parser::Parser;
+use crate::scanner::Scanner;
+
+// Consulted by `Interpreter::execute` before every statement that starts on
+// a line, with the id of the file it is in (0 for the script). Returning an
+// error stops the script.
+pub trait DebugHook {
+    fn before_statement(&self, interpreter: &Interpreter, file: usize, line: usize) -> Result<(), Box<dyn Any>>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepMode {
+    // Stop before the first statement.
+    Entry,
+    Continue,
+    // Stop before the next statement anywhere.
+    StepIn,
+    // Stop before the next statement at or above this call depth.
+    StepOver(usize),
+    // Stop before the next statement above this call depth.
+    StepOut(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Entry,
+    Step,
+    Breakpoint,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    // Matched against the end of the script path; `None` matches any script.
+    pub file: Option<String>,
+    pub line: usize,
+}
+
+impl Breakpoint {
+    // Parses "line" or "file:line".
+    pub fn parse(spec: &str) -> Option<Breakpoint> {
+        let (file, line) = match spec.rsplit_once(':') {
+            Some((file, line)) => (Some(file.to_string()), line),
+            None => (None, spec),
+        };
+        let line = line.trim().parse().ok()?;
+        Some(Breakpoint { file, line })
+    }
+
+    pub fn matches(&self, path: &str, line: usize) -> bool {
+        self.line == line && self.file.as_ref().is_none_or(|file| path.ends_with(file.as_str()))
+    }
+}
+
+impl std::fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file, self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+// Breakpoints and stepping, shared by the debugger front ends.
+pub struct DebugState {
+    pub path: String,
+    pub breakpoints: Vec<Breakpoint>,
+    pub mode: StepMode,
+    // (file, line, depth) of the previous statement, so statements nested
+    // on one line, like `if (x) return;`, only stop once.
+    previous: Option<(usize, usize, usize)>,
+}
+
+impl DebugState {
+    pub fn new(path: &str, mode: StepMode) -> Self {
+        Self { path: path.to_string(), breakpoints: vec![], mode, previous: None }
+    }
+
+    // Path of `file`: as the interpreter found it, or `path` for a script
+    // run from source.
+    pub fn file_path(&self, interpreter: &Interpreter, file: usize) -> String {
+        interpreter.file_path(file).unwrap_or_else(|| self.path.clone())
+    }
+
+    // Why to stop before a statement on `line` of `file`, found at `path`,
+    // at call `depth`, if at all.
+    pub fn stop_reason(&mut self, file: usize, path: &str, line: usize, depth: usize) -> Option<StopReason> {
+        let moved = self.previous != Some((file, line, depth));
+        self.previous = Some((file, line, depth));
+        if !moved {
+            return None;
+        }
+        let stepped = match self.mode {
+            StepMode::Entry => return Some(StopReason::Entry),
+            StepMode::Continue => false,
+            StepMode::StepIn => true,
+            StepMode::StepOver(over) => depth <= over,
+            StepMode::StepOut(out) => depth < out,
+        };
+        if stepped {
+            Some(StopReason::Step)
+        } else if self.breakpoints.iter().any(|breakpoint| breakpoint.matches(path, line)) {
+            Some(StopReason::Breakpoint)
+        } else {
+            None
+        }
+    }
+}
+
+// Evaluates `text` as an expression in `scope`, or in the current scope.
+// Names the resolver never saw are looked up dynamically through the scope
+// chain, so locals of the paused frame are visible.
+pub fn evaluate(interpreter: &Interpreter, text: &str, scope: Option<usize>) -> Result<LoxValue, String> {
+    let mut tokens = Scanner::build(text).scan_tokens();
+    // Line 0 keeps these expressions from colliding with resolved ones.
+    for token in tokens.iter_mut() {
+        token.line = 0;
+    }
+    let expr = Parser::new(tokens).parse_expression().map_err(|err| err.0)?;
+    let previous = scope.map(|scope| interpreter.environment.borrow_mut().switch_to(scope));
+    let result = interpreter.evaluate(expr);
+    if let Some(previous) = previous {
+        interpreter.environment.borrow_mut().switch_back(previous);
+    }
+    result.map_err(|err| {
+        if let Some(err) = err.downcast_ref::<RuntimeError>() {
+            err.message.clone()
+        } else if let Some(err) = err.downcast_ref::<RuntimeInterrupt>() {
+            err.to_string()
+        } else {
+            "Evaluation failed.".to_string()
+        }
+    })
+}
+
+// How the debuggers show a value: like `print`, but with strings quoted.
+pub fn describe(value: &LoxValue) -> String {
+    match value {
+        LoxValue::String(s) => format!("{:?}", s),
+        value => value.to_string(),
+    }
+}
+
+const HELP: &str = "\
+step, s             stop at the next statement
+next, n             stop at the next statement in this function
+finish, f           run until this function returns
+continue, c         run until a breakpoint
+break, b [FILE:]N   set a breakpoint; list breakpoints without arguments
+delete, d [N]       delete breakpoint N, or all of them
+backtrace, bt       show the call stack
+print, p EXPR       evaluate an expression in the current frame
+locals              show the variables in scope
+list, l             show the source around the current line
+quit, q             stop the script";
+
+// Line-oriented debugger for `--debug`, driven from a terminal prompt.
+pub struct TerminalDebugger {
+    state: RefCell<DebugState>,
+    source: Vec<String>,
+    input: RefCell<Box<dyn BufRead>>,
+    output: RefCell<Box<dyn Write>>,
+    last_command: RefCell<String>,
+    // Set while a `print` runs, so calls it makes don't stop again.
+    evaluating: Cell<bool>,
+}
+
+impl TerminalDebugger {
+    pub fn new(path: &str, source: &str) -> Self {
+        let input = Box::new(std::io::BufReader::new(std::io::stdin()));
+        Self::with_io(path, source, input, Box::new(std::io::stdout()))
+    }
+
+    pub fn with_io(path: &str, source: &str, input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Self {
+            state: RefCell::new(DebugState::new(path, StepMode::Entry)),
+            source: source.lines().map(str::to_string).collect(),
+            input: RefCell::new(input),
+            output: RefCell::new(output),
+            last_command: RefCell::new(String::new()),
+            evaluating: Cell::new(false),
+        }
+    }
+
+    fn say(&self, text: &str) {
+        let mut output = self.output.borrow_mut();
+        // A debugger that cannot write to its terminal has nothing better to do.
+        let _ = writeln!(output, "{}", text);
+    }
+
+    // The lines of the file at `path`: the script's own source, or an
+    // imported module read from disk.
+    fn source_lines(&self, path: &str) -> Vec<String> {
+        if path == self.state.borrow().path {
+            return self.source.clone();
+        }
+        std::fs::read_to_string(path).map(|source| source.lines().map(str::to_string).collect()).unwrap_or_default()
+    }
+
+    fn source_line(&self, path: &str, line: usize) -> String {
+        self.source_lines(path).get(line.wrapping_sub(1)).cloned().unwrap_or_default()
+    }
+
+    fn read_command(&self) -> Option<String> {
+        {
+            let mut output = self.output.borrow_mut();
+            let _ = write!(output, "(lox) ");
+            let _ = output.flush();
+        }
+        let mut command = String::new();
+        match self.input.borrow_mut().read_line(&mut command) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => {}
+        }
+        let command = command.trim().to_string();
+        if command.is_empty() {
+            return Some(self.last_command.borrow().clone());
+        }
+        *self.last_command.borrow_mut() = command.clone();
+        Some(command)
+    }
+
+    // Reads commands until one resumes the script.
+    fn prompt(&self, interpreter: &Interpreter, path: &str, line: usize) -> Result<(), Box<dyn Any>> {
+        let depth = interpreter.call_stack().len();
+        loop {
+            let Some(command) = self.read_command() else {
+                return Err(RuntimeInterrupt::new(InterruptKind::Cancelled).into());
+            };
+            let (verb, rest) = command.split_once(' ').unwrap_or((command.as_str(), ""));
+            let rest = rest.trim();
+            let mode = match verb {
+                "step" | "s" => StepMode::StepIn,
+                "next" | "n" => StepMode::StepOver(depth),
+                "finish" | "f" => StepMode::StepOut(depth),
+                "continue" | "c" => StepMode::Continue,
+                "quit" | "q" => return Err(RuntimeInterrupt::new(InterruptKind::Cancelled).into()),
+                _ => {
+                    self.inspect(interpreter, path, line, verb, rest);
+                    continue;
+                }
+            };
+            self.state.borrow_mut().mode = mode;
+            return Ok(());
+        }
+    }
+
+    // Commands that look around without resuming.
+    fn inspect(&self, interpreter: &Interpreter, path: &str, line: usize, verb: &str, rest: &str) {
+        match verb {
+            "break" | "b" if rest.is_empty() => {
+                let state = self.state.borrow();
+                if state.breakpoints.is_empty() {
+                    self.say("No breakpoints.");
+                }
+                for (i, breakpoint) in state.breakpoints.iter().enumerate() {
+                    self.say(&format!("{}: {}", i + 1, breakpoint));
+                }
+            }
+            "break" | "b" => match Breakpoint::parse(rest) {
+                Some(breakpoint) => {
+                    let mut state = self.state.borrow_mut();
+                    state.breakpoints.push(breakpoint);
+                    self.say(&format!("Breakpoint {} at {}", state.breakpoints.len(), state.breakpoints.last().unwrap()));
+                }
+                None => self.say("Usage: break [FILE:]LINE"),
+            },
+            "delete" | "d" if rest.is_empty() => {
+                self.state.borrow_mut().breakpoints.clear();
+                self.say("Deleted all breakpoints.");
+            }
+            "delete" | "d" => {
+                let mut state = self.state.borrow_mut();
+                match rest.parse::<usize>() {
+                    Ok(n) if n >= 1 && n <= state.breakpoints.len() => {
+                        state.breakpoints.remove(n - 1);
+                        self.say(&format!("Deleted breakpoint {}.", n));
+                    }
+                    _ => self.say(&format!("No breakpoint {}.", rest)),
+                }
+            }
+            "backtrace" | "bt" => {
+                for entry in interpreter.stack_trace(line) {
+                    self.say(&entry);
+                }
+            }
+            "print" | "p" => {
+                self.evaluating.set(true);
+                let result = evaluate(interpreter, rest, None);
+                self.evaluating.set(false);
+                match result {
+                    Ok(value) => self.say(&describe(&value)),
+                    Err(message) => self.say(&message),
+                }
+            }
+            "locals" => {
+                let env = interpreter.environment.borrow();
+                for scope in env.scope_chain(env.current()) {
+                    let bindings = env.bindings(scope);
+                    if bindings.is_empty() && !env.is_global(scope) {
+                        continue;
+                    }
+                    let label = if env.is_global(scope) { "globals" } else { "scope" };
+                    self.say(&format!("{}:", label));
+                    for (name, value) in bindings {
+                        self.say(&format!("  {} = {}", name, describe(&value)));
+                    }
+                }
+            }
+            "list" | "l" => {
+                let source = self.source_lines(path);
+                let first = line.saturating_sub(5).max(1);
+                let last = (line + 5).min(source.len());
+                for n in first..=last {
+                    let marker = if n == line { "->" } else { "  " };
+                    self.say(&format!("{} {:>4}  {}", marker, n, source[n - 1]));
+                }
+            }
+            "help" | "h" => self.say(HELP),
+            _ => self.say(&format!("Unknown command '{}'. Try 'help'.", verb)),
+        }
+    }
+}
+
+impl DebugHook for TerminalDebugger {
+    fn before_statement(&self, interpreter: &Interpreter, file: usize, line: usize) -> Result<(), Box<dyn Any>> {
+        if self.evaluating.get() {
+            return Ok(());
+        }
+        let depth = interpreter.call_stack().len();
+        let path = self.state.borrow().file_path(interpreter, file);
+        let Some(reason) = self.state.borrow_mut().stop_reason(file, &path, line, depth) else {
+            return Ok(());
+        };
+        let why = match reason {
+            StopReason::Entry => "Stopped at entry",
+            StopReason::Step => "Stopped",
+            StopReason::Breakpoint => "Breakpoint hit",
+        };
+        let function = interpreter.call_stack().last().map_or("script".to_string(), |frame| format!("{}()", frame.function));
+        self.say(&format!("{} in {} at {}:{}", why, function, path, line));
+        self.say(&format!("{:>4}  {}", line, self.source_line(&path, line)));
+        self.prompt(interpreter, &path, line)
+    }
+}
+
+// Collects what a debugger writes, for tests.
+#[cfg(test)]
+pub(crate) struct SharedOutput(pub(crate) std::rc::Rc<RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_terminal_debugger() {
+    use std::rc::Rc;
+
+    let source = "fun add(a, b) {\n  var sum = a + b;\n  return sum;\n}\nvar x = 1;\nvar y = add(x, 2);\nvar z = y * 2;\n";
+    let commands = "b 3\nc\np sum + 1\nbt\nlocals\nfinish\np y\nc\n";
+    let output = Rc::new(RefCell::new(vec![]));
+    let debugger = TerminalDebugger::with_io(
+        "test.lox",
+        source,
+        Box::new(std::io::Cursor::new(commands)),
+        Box::new(SharedOutput(output.clone())),
+    );
+
+    let mut lox = crate::Lox::new();
+    lox.interpreter().set_debug_hook(Some(Rc::new(debugger)));
+    lox.run(source);
+    assert!(!lox.had_runtime_error);
+
+    let output = String::from_utf8(output.borrow().clone()).unwrap();
+    let expected = [
+        "Stopped at entry in script at test.lox:1",
+        "Breakpoint 1 at line 3",
+        "Breakpoint hit in add() at test.lox:3",
+        "(lox) 4\n",
+        "[line 3] in add()\n[line 6] in script\n",
+        "scope:\n  sum = 3\nscope:\n  a = 1\n  b = 2\nglobals:\n",
+        "Stopped in script at test.lox:7",
+        "(lox) 3\n",
+    ];
+    let mut rest = output.as_str();
+    for text in expected {
+        let at = rest.find(text).unwrap_or_else(|| panic!("{:?} not found in {:?}", text, rest));
+        rest = &rest[at + text.len()..];
+    }
+    assert_eq!(lox.interpreter().environment.borrow().get("z".into()).unwrap(), LoxValue::Int(6));
+}
+
+#[test]
+fn test_breakpoints_in_modules() {
+    use std::rc::Rc;
+
+    let path = "./tests/modules/main.lox";
+    let source = std::fs::read_to_string(path).unwrap();
+    // Line 7 of counter.lox runs on every `bump`, but only main.lox:7 is set.
+    let commands = "b main.lox:7\nb counter.lox:4\nc\nbt\nc\nc\n";
+    let output = Rc::new(RefCell::new(vec![]));
+    let debugger =
+        TerminalDebugger::with_io(path, &source, Box::new(std::io::Cursor::new(commands)), Box::new(SharedOutput(output.clone())));
+
+    let mut lox = crate::Lox::new();
+    lox.interpreter().set_print_handler(Some(Box::new(|_: &str| {})));
+    lox.interpreter().set_debug_hook(Some(Rc::new(debugger)));
+    lox.run_file(path);
+    assert!(!lox.had_runtime_error);
+
+    let output = String::from_utf8(output.borrow().clone()).unwrap();
+    let stops: Vec<&str> = output.lines().filter(|line| line.contains(" in script at ")).filter_map(|line| line.rsplit(" at ").next()).collect();
+    assert_eq!(stops, ["./tests/modules/main.lox:1", "./tests/modules/counter.lox:4", "./tests/modules/main.lox:7"]);
+    assert!(output.contains("   4  export var count = 0;\n"), "{}", output);
+}
@@ -1,35 +1,144 @@
-use std::{collections::HashMap, rc::Rc, vec};
+use std::{collections::HashSet, vec};
 
-use crate::{ast::LoxValue, errors::RuntimeError};
+use crate::{ast::LoxValue, errors::RuntimeError, interner::{Symbol, SymbolMap}, memory};
 
+// What a scope stores per binding. With the `nan-boxing` feature values are
+// packed into a single word; otherwise they are kept as they are.
+#[cfg(not(feature = "nan-boxing"))]
+pub(crate) type Slot = LoxValue;
+#[cfg(feature = "nan-boxing")]
+pub(crate) type Slot = crate::nanbox::NanBox;
+
+#[cfg(not(feature = "nan-boxing"))]
+fn store(value: LoxValue) -> Slot {
+    value
+}
+#[cfg(feature = "nan-boxing")]
+fn store(value: LoxValue) -> Slot {
+    value.into()
+}
+
+#[cfg(not(feature = "nan-boxing"))]
+fn load(slot: &Slot) -> LoxValue {
+    slot.clone()
+}
+#[cfg(feature = "nan-boxing")]
+fn load(slot: &Slot) -> LoxValue {
+    slot.get()
+}
+
+// Scopes live in `registry` and refer to their parent by index in
+// `ancestor`. Closures hold the index of the scope they were declared in,
+// so scopes are reclaimed by a mark-sweep collector rather than on exit:
+// `collect` keeps every scope reachable from the global scope, the current
+// scope chain, the scopes of suspended callers, imported modules and any
+// temporary roots, and recycles the rest through `free`.
+//
+// Native functions live in their own scope, `NATIVES`, which is the parent
+// of both the script's global scope (0) and each module's top-level scope.
 pub struct Environment {
-    registry: Vec<HashMap<String, Rc<LoxValue>>>,
+    registry: Vec<SymbolMap<Slot>>,
     ancestor: Vec<usize>,
     curregis: usize,
     curdepth: usize,
+    // Scope of each caller suspended by `enter_closure`, innermost last.
+    callers: Vec<usize>,
+    // Values that are only held on the Rust stack, e.g. a callee and its
+    // arguments while they are being evaluated.
+    roots: Vec<LoxValue>,
+    free: Vec<usize>,
+    marks: Vec<bool>,
+    // Top-level scopes of imported modules. Modules are cached for the
+    // life of the interpreter, so these are always roots.
+    modules: Vec<usize>,
+    // `const` bindings by scope. The resolver rejects assignments to the
+    // ones it can see; this catches late-bound globals.
+    constants: HashSet<(usize, Symbol)>,
+}
+
+// Result of one collection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Collection {
+    pub scopes_freed: usize,
+    pub live_scopes: usize,
+    // Estimated bytes held by everything that survived.
+    pub reachable_bytes: usize,
+}
+
+const NATIVES: usize = 1;
+
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Environment {
     pub fn new() -> Environment {
-        let body = Self {
-            registry: vec![HashMap::new()],
-            ancestor: vec![usize::MAX],
+        Self {
+            registry: vec![SymbolMap::default(), SymbolMap::default()],
+            ancestor: vec![NATIVES, usize::MAX],
             curregis: 0,
             curdepth: 0,
-        };
-        return body;
+            callers: vec![],
+            roots: vec![],
+            free: vec![],
+            marks: vec![false, false],
+            modules: vec![],
+            constants: HashSet::new(),
+        }
     }
 
-    pub fn enter_scope(&mut self, is_global: bool) {
-        // dbg!("enter_scope");
-        if is_global {
-            self.ancestor.push(0);
+    fn allocate_scope(&mut self, ancestor: usize) -> usize {
+        if let Some(index) = self.free.pop() {
+            self.ancestor[index] = ancestor;
+            index
         } else {
-            self.ancestor.push(self.registry.len() - 1);
+            self.registry.push(SymbolMap::default());
+            self.ancestor.push(ancestor);
+            self.marks.push(false);
+            self.registry.len() - 1
         }
-        self.registry.push(HashMap::new());
+    }
+
+    pub fn enter_scope(&mut self, is_global: bool) {
+        // dbg!("enter_scope");
+        let ancestor = if is_global { 0 } else { self.curregis };
+        self.curregis = self.allocate_scope(ancestor);
+        self.curdepth += 1;
+    }
+
+    // Opens a call scope whose parent is the function's closure rather than
+    // the caller's scope. Returns the caller's scope for `restore`.
+    pub fn enter_closure(&mut self, closure: usize) -> usize {
+        let previous = self.curregis;
+        self.callers.push(previous);
+        self.curregis = self.allocate_scope(closure);
         self.curdepth += 1;
-        self.curregis = self.registry.len() - 1;
+        previous
+    }
+
+    pub fn restore(&mut self, previous: usize) {
+        self.callers.pop();
+        self.curdepth -= 1;
+        self.curregis = previous;
+    }
+
+    // A new top-level scope for a module. It sees the natives but not the
+    // script's globals or any other module's.
+    pub fn module_scope(&mut self) -> usize {
+        let scope = self.allocate_scope(NATIVES);
+        self.modules.push(scope);
+        scope
+    }
+
+    // `name` as bound directly in `scope`, without looking further out.
+    pub fn get_in(&self, scope: usize, name: Symbol) -> Option<LoxValue> {
+        self.registry[scope].get(&name).map(load)
+    }
+
+    pub fn current(&self) -> usize {
+        self.curregis
     }
 
     pub fn exit_scope(&mut self) {
@@ -38,62 +147,188 @@ impl Environment {
         self.curregis = self.ancestor[self.curregis];
     }
 
-    pub fn define(&mut self, name: &str, value: Rc<LoxValue>) {
-        self.registry[self.curregis].insert(name.to_string(), value);
+    pub fn push_root(&mut self, value: LoxValue) {
+        self.roots.push(value);
+    }
+
+    pub fn pop_roots(&mut self, count: usize) {
+        self.roots.truncate(self.roots.len() - count);
+    }
+
+    // Scope each active Lox call is executing in, innermost first and
+    // ending with the scope top-level code was running in.
+    pub fn frame_scopes(&self) -> Vec<usize> {
+        let mut scopes = vec![self.curregis];
+        scopes.extend(self.callers.iter().rev());
+        scopes
+    }
+
+    // `scope` followed by its ancestors, ending with the global scope (or a
+    // module's top-level scope). The natives are left out.
+    pub fn scope_chain(&self, scope: usize) -> Vec<usize> {
+        let mut chain = vec![];
+        let mut index = scope;
+        while index != usize::MAX && index != NATIVES {
+            chain.push(index);
+            index = self.ancestor[index];
+        }
+        chain
+    }
+
+    pub fn is_global(&self, scope: usize) -> bool {
+        scope == 0
+    }
+
+    // The bindings held directly by `scope`, sorted by name.
+    pub fn bindings(&self, scope: usize) -> Vec<(Symbol, LoxValue)> {
+        let mut bindings: Vec<(Symbol, LoxValue)> =
+            self.registry[scope].iter().map(|(name, slot)| (*name, load(slot))).collect();
+        bindings.sort_by_key(|(name, _)| name.as_str());
+        bindings
+    }
+
+    // Makes `scope` current, e.g. to evaluate an expression in a caller's
+    // frame. The displaced scope stays rooted until `switch_back`.
+    pub fn switch_to(&mut self, scope: usize) -> usize {
+        let previous = self.curregis;
+        self.callers.push(previous);
+        self.curregis = scope;
+        previous
+    }
+
+    pub fn switch_back(&mut self, previous: usize) {
+        self.callers.pop();
+        self.curregis = previous;
+    }
+
+    pub fn live_scopes(&self) -> usize {
+        self.registry.len() - self.free.len()
     }
 
-    pub fn get(&self, name: &str) -> Result<Rc<LoxValue>, RuntimeError> {
+    pub fn collect(&mut self) -> Collection {
+        // Mark.
+        let mut pending = vec![0, self.curregis];
+        pending.extend(self.callers.iter().copied());
+        pending.extend(self.modules.iter().copied());
+        pending.extend(self.roots.iter().filter_map(closure_of));
+        while let Some(index) = pending.pop() {
+            if index == usize::MAX || self.marks[index] {
+                continue;
+            }
+            self.marks[index] = true;
+            pending.push(self.ancestor[index]);
+            pending.extend(self.registry[index].values().filter_map(|slot| closure_of(&load(slot))));
+        }
+
+        // Sweep, measuring the survivors on the way.
+        let mut collection = Collection::default();
+        let mut seen: HashSet<*const ()> = HashSet::new();
+        let free: HashSet<usize> = self.free.iter().copied().collect();
+        for index in 0..self.registry.len() {
+            if free.contains(&index) {
+                continue;
+            }
+            if !self.marks[index] {
+                self.registry[index] = SymbolMap::default();
+                self.ancestor[index] = usize::MAX;
+                self.constants.retain(|&(scope, _)| scope != index);
+                self.free.push(index);
+                collection.scopes_freed += 1;
+                continue;
+            }
+            self.marks[index] = false;
+            collection.live_scopes += 1;
+            collection.reachable_bytes += memory::scope_size();
+            for slot in self.registry[index].values() {
+                collection.reachable_bytes += memory::binding_size();
+                let value = load(slot);
+                // Shared strings and functions are counted once.
+                if heap_ptr(&value).is_some_and(|ptr| seen.insert(ptr)) {
+                    collection.reachable_bytes += memory::value_size(&value);
+                }
+            }
+        }
+        collection
+    }
+
+    pub fn define_native(&mut self, name: Symbol, value: LoxValue) {
+        self.registry[NATIVES].insert(name, store(value));
+    }
+
+    pub fn define(&mut self, name: Symbol, value: LoxValue) {
+        self.constants.remove(&(self.curregis, name));
+        self.registry[self.curregis].insert(name, store(value));
+    }
+
+    pub fn define_constant(&mut self, name: Symbol, value: LoxValue) {
+        self.constants.insert((self.curregis, name));
+        self.registry[self.curregis].insert(name, store(value));
+    }
+
+    pub fn get(&self, name: Symbol) -> Result<LoxValue, RuntimeError> {
         let mut regist_index = self.curregis;
         while regist_index != usize::MAX {
-            if let Some(value) = self.registry[regist_index].get(name) {
-                return Ok(value.clone());
+            if let Some(value) = self.registry[regist_index].get(&name) {
+                return Ok(load(value));
             }
             regist_index = self.ancestor[regist_index];
         }
-        Err(RuntimeError("Undefined variable '".to_string() + name + "'."))
+        Err(RuntimeError::bare(&format!("Undefined variable '{}'.", name)))
     }
-    pub fn get_at(&self, distance: usize, name: &str) -> Result<Rc<LoxValue>, RuntimeError> {
+    pub fn get_at(&self, distance: usize, name: Symbol) -> Result<LoxValue, RuntimeError> {
         let mut regist_index = self.curregis;
         for _ in 0..distance {
             regist_index = self.ancestor[regist_index];
         }
-        if let Some(value) = self.registry[regist_index].get(name) {
-            return Ok(value.clone());
+        if let Some(value) = self.registry[regist_index].get(&name) {
+            return Ok(load(value));
         }
-        Err(RuntimeError(
-            "Undefined variable '".to_string() + name + "'.",
-        ))
+        Err(RuntimeError::bare(&format!("Undefined variable '{}'.", name)))
     }
 
-    pub fn assign(&mut self, name: &str, value: Rc<LoxValue>) -> Result<(), RuntimeError> {
+    pub fn assign(&mut self, name: Symbol, value: LoxValue) -> Result<(), RuntimeError> {
         let mut regist_index = self.curregis;
         while regist_index != usize::MAX {
-            if self.registry[regist_index].contains_key(name) {
-                self.registry[regist_index].insert(name.to_string(), value);
+            if let Some(slot) = self.registry[regist_index].get_mut(&name) {
+                if self.constants.contains(&(regist_index, name)) {
+                    return Err(RuntimeError::bare(&format!("Can't assign to constant '{}'.", name)));
+                }
+                *slot = store(value);
                 return Ok(());
             }
             regist_index = self.ancestor[regist_index];
         }
-        Err(RuntimeError(
-            name.to_string() + "Undefined variable '" + name + "'.",
-        ))
+        Err(RuntimeError::bare(&format!("Undefined variable '{}'.", name)))
     }
     pub fn assign_at(
         &mut self,
         distance: usize,
-        name: &str,
-        value: Rc<LoxValue>,
+        name: Symbol,
+        value: LoxValue,
     ) -> Result<(), RuntimeError> {
         let mut regist_index = self.curregis;
         for _ in 0..distance {
             regist_index = self.ancestor[regist_index];
         }
-        if self.registry[regist_index].contains_key(name) {
-            self.registry[regist_index].insert(name.to_string(), value);
+        if let Some(slot) = self.registry[regist_index].get_mut(&name) {
+            *slot = store(value);
             return Ok(());
         }
-        Err(RuntimeError(
-            name.to_string() + "Undefined variable '" + name + "'.",
-        ))
+        Err(RuntimeError::bare(&format!("Undefined variable '{}'.", name)))
+    }
+}
+
+fn closure_of(value: &LoxValue) -> Option<usize> {
+    match value {
+        LoxValue::Callable(callable) => callable.closure(),
+        _ => None,
+    }
+}
+
+fn heap_ptr(value: &LoxValue) -> Option<*const ()> {
+    match value {
+        LoxValue::String(s) => Some(s.as_ptr() as *const ()),
+        LoxValue::Callable(callable) => Some(std::rc::Rc::as_ptr(callable) as *const ()),
+        _ => None,
     }
 }
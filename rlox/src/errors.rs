@@ -1,36 +1,105 @@
 use crate::{ast::LoxValue, token::Token};
-use std::{fmt::{Debug, Display, Formatter, Result}, rc::Rc};
-
-pub trait RuntimeErrorT: Display {}
+use std::{any::Any, fmt::{Debug, Display, Formatter, Result}};
 
 // -------------------------------------------------------
-pub struct RuntimeError(pub String);
-
-impl RuntimeErrorT for RuntimeError{}
+pub struct RuntimeError {
+    pub message: String,
+    // (line, column) of the token that raised the error, if any.
+    pub location: Option<(usize, usize)>,
+    // One "[line N] in f()" entry per active frame, innermost first.
+    pub trace: Vec<String>,
+}
 
 impl Display for RuntimeError {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        write!(f, "{}", self.0)
+        match self.location {
+            Some((line, column)) => write!(f, "RuntimeError at line {} column {}: {}", line, column, self.message)?,
+            None => write!(f, "RuntimeError: {}", self.message)?,
+        }
+        for entry in self.trace.iter() {
+            write!(f, "\n{}", entry)?;
+        }
+        Ok(())
     }
 }
 
-
 impl Debug for RuntimeError {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        write!(f, "{}", self.0)
+        write!(f, "{}", self)
+    }
+}
+
+impl From<RuntimeError> for Box<dyn Any> {
+    fn from(err: RuntimeError) -> Self {
+        Box::new(err)
     }
 }
 
 impl RuntimeError {
     pub fn new(token: &Token, message: &str) -> RuntimeError {
-        RuntimeError(format!("RuntimeError at line {} column {}: {}", token.line, token.offset, message))
+        RuntimeError {
+            message: message.to_string(),
+            location: Some((token.line, token.offset)),
+            trace: vec![],
+        }
+    }
+    pub fn bare(message: &str) -> RuntimeError {
+        RuntimeError {
+            message: message.to_string(),
+            location: None,
+            trace: vec![],
+        }
+    }
+    pub fn line(&self) -> Option<usize> {
+        self.location.map(|(line, _)| line)
     }
 }
 
 // -------------------------------------------------------
-pub struct RuntimeReturn(pub Rc<LoxValue>);
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterruptKind {
+    OutOfFuel,
+    DeadlineExceeded,
+    Cancelled,
+}
 
-impl RuntimeErrorT for RuntimeReturn{}
+// Raised when the embedder's execution budget runs out. Kept apart from
+// `RuntimeError` so hosts can tell a stopped script from a faulty one.
+pub struct RuntimeInterrupt {
+    pub kind: InterruptKind,
+}
+
+impl Display for RuntimeInterrupt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let reason = match self.kind {
+            InterruptKind::OutOfFuel => "out of fuel",
+            InterruptKind::DeadlineExceeded => "deadline exceeded",
+            InterruptKind::Cancelled => "cancelled",
+        };
+        write!(f, "Execution interrupted: {}.", reason)
+    }
+}
+
+impl Debug for RuntimeInterrupt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "{}", self)
+    }
+}
+
+impl From<RuntimeInterrupt> for Box<dyn Any> {
+    fn from(err: RuntimeInterrupt) -> Self {
+        Box::new(err)
+    }
+}
+
+impl RuntimeInterrupt {
+    pub fn new(kind: InterruptKind) -> RuntimeInterrupt {
+        RuntimeInterrupt { kind }
+    }
+}
+
+// -------------------------------------------------------
+pub struct RuntimeReturn(pub LoxValue);
 
 impl Display for RuntimeReturn {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result {
@@ -45,7 +114,7 @@ impl Debug for RuntimeReturn {
 }
 
 impl RuntimeReturn {
-    pub fn new(_value: Rc<LoxValue>) -> RuntimeReturn {
+    pub fn new(_value: LoxValue) -> RuntimeReturn {
         RuntimeReturn(_value)
     }
 }
@@ -53,13 +122,61 @@ impl RuntimeReturn {
 
 
 
+// The formatted message and the token the parser stopped at.
 #[derive(Debug)]
-pub struct ParseError(pub String);
+pub struct ParseError(pub String, pub Option<Token>);
 
 impl ParseError {
     pub fn new(token: Option<&Token>, message: &str) -> ParseError {
         let token = token.unwrap();
-        ParseError(format!("ParseError at line {} column {}: {}", token.line, token.offset, message))
+        ParseError(format!("ParseError at line {} column {}: {}", token.line, token.offset, message), Some(token.clone()))
+    }
+}
+
+// A character the scanner could not turn into a token. `offset` is where
+// the scanner was when it gave up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LexError {
+    pub message: String,
+    pub line: usize,
+    pub offset: usize,
+}
+
+impl Display for LexError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        write!(f, "line {}, {}", self.line, self.message)
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+// A static problem found by the resolver, such as reading a local in its
+// own initializer. Warnings, like an unused local, don't stop the script
+// from running.
+#[derive(Debug, Clone)]
+pub struct ResolveError {
+    pub token: Token,
+    pub message: String,
+    pub severity: Severity,
+}
+
+impl Display for ResolveError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        let kind = match self.severity {
+            Severity::Error => "ResolveError",
+            Severity::Warning => "ResolveWarning",
+        };
+        write!(f, "{} at line {} column {}: {}", kind, self.token.line, self.token.offset, self.message)
+    }
+}
+
+impl ResolveError {
+    // The chars it covers, `start..end`.
+    pub fn span(&self) -> (usize, usize) {
+        (self.token.start(), self.token.offset)
+    }
+}
@@ -0,0 +1,297 @@
+use crate::parser::Parser;
+use crate::scanner::Scanner;
+use crate::token::{Token, TokenType};
+
+// Canonical layout for Lox source, as used by `jlox fmt`. The program is
+// parsed first so only valid code is touched, but the output is produced
+// from the token stream: the AST has already desugared `for` loops and
+// dropped the comments.
+
+const INDENT: &str = "  ";
+
+// Formats `source`, or returns the first scan or parse error.
+pub fn format_source(source: &str) -> Result<String, String> {
+    let (tokens, errors) = Scanner::build(source).with_trivia().scan();
+    if let Some(error) = errors.first() {
+        return Err(error.to_string());
+    }
+    let code = tokens.iter().filter(|token| !matches!(token._type, TokenType::COMMENT | TokenType::WHITESPACE));
+    Parser::new(code.cloned().collect()).parse().map_err(|err| err.0)?;
+
+    let stream: Vec<Token> =
+        tokens.into_iter().filter(|token| !matches!(token._type, TokenType::WHITESPACE | TokenType::EOF)).collect();
+
+    let mut formatter = Formatter::default();
+    for (i, token) in stream.iter().enumerate() {
+        formatter.token(token, stream.get(i + 1));
+    }
+    Ok(formatter.finish())
+}
+
+// Whether a token ends an operand, so a following `-` is binary and a
+// following `(` is a call.
+fn ends_operand(token: &TokenType) -> bool {
+    matches!(
+        token,
+        TokenType::IDENTIFIER
+            | TokenType::NUMBER
+            | TokenType::STRING
+            | TokenType::TRUE
+            | TokenType::FALSE
+            | TokenType::NIL
+            | TokenType::THIS
+            | TokenType::RIGHTPAREN
+    )
+}
+
+#[derive(Default)]
+struct Formatter {
+    out: String,
+    braces: usize,
+    parens: usize,
+    // Paren depths at which an `if`, `while` or `for` header opened, and
+    // whether it was an `if`.
+    headers: Vec<(usize, bool)>,
+    // Brace depth of each enclosing statement whose body has no braces and
+    // so is indented on its own line, and whether it is an `if` branch an
+    // `else` can still follow.
+    bodies: Vec<(usize, bool)>,
+    // For each open brace, whether it is the braced body of an `if`.
+    blocks: Vec<bool>,
+    // The next `{` opens the body of an `if`.
+    if_block: bool,
+    // The last `}` closed the body of an `if`, so an `else` joins its line.
+    closed_if_block: bool,
+    // Line breaks are only written before the next token, so a trailing
+    // comment can still join the line it follows.
+    newline: bool,
+    previous: Option<TokenType>,
+    // Whether the previous token was a prefix operator.
+    previous_unary: bool,
+    // Whether the previous token ended an operand, including a postfix
+    // `++` or `--`.
+    previous_operand: bool,
+    last_line: usize,
+    // A statement is under way, so a line break inside it starts a
+    // continuation line, indented one level further.
+    in_statement: bool,
+    // A `/* */` comment was just written with code following on its line.
+    after_comment: bool,
+}
+
+impl Formatter {
+    fn token(&mut self, token: &Token, next: Option<&Token>) {
+        let kind = &token._type;
+        let next_kind = next.map(|next| &next._type);
+        if matches!(kind, TokenType::COMMENT | TokenType::DOCCOMMENT) {
+            return self.comment(token, next);
+        }
+        match kind {
+            TokenType::RIGHTBRACE => {
+                self.braces = self.braces.saturating_sub(1);
+                self.bodies.retain(|(depth, _)| *depth <= self.braces);
+                self.closed_if_block = self.blocks.pop().unwrap_or(false);
+            }
+            TokenType::ELSE if self.previous == Some(TokenType::RIGHTBRACE) && self.closed_if_block => {
+                self.newline = false
+            }
+            _ => {}
+        }
+
+        let space = self.after_comment || self.space_before(token);
+        self.write(token, space);
+        self.after_comment = false;
+
+        let postfix = matches!(kind, TokenType::PLUSPLUS | TokenType::MINUSMINUS) && self.previous_operand;
+        self.previous_unary = match kind {
+            TokenType::BANG | TokenType::TILDE => true,
+            TokenType::MINUS | TokenType::PLUSPLUS | TokenType::MINUSMINUS => !self.previous_operand,
+            _ => false,
+        };
+        self.previous_operand = postfix || ends_operand(kind);
+        self.in_statement = true;
+
+        match kind {
+            TokenType::LEFTPAREN => {
+                if matches!(self.previous, Some(TokenType::IF | TokenType::WHILE | TokenType::FOR)) {
+                    self.headers.push((self.parens, self.previous == Some(TokenType::IF)));
+                }
+                self.parens += 1;
+            }
+            TokenType::RIGHTPAREN => {
+                self.parens = self.parens.saturating_sub(1);
+                if let Some(&(_, is_if)) = self.headers.last().filter(|(depth, _)| *depth == self.parens) {
+                    self.headers.pop();
+                    self.open_body(next_kind, is_if);
+                }
+            }
+            TokenType::LEFTBRACE => {
+                self.braces += 1;
+                self.in_statement = false;
+                self.blocks.push(std::mem::take(&mut self.if_block));
+                self.newline = next_kind != Some(&TokenType::RIGHTBRACE);
+            }
+            TokenType::RIGHTBRACE => self.end_statement(next_kind, self.closed_if_block),
+            TokenType::SEMICOLON if self.parens == 0 => self.end_statement(next_kind, false),
+            TokenType::ELSE if next_kind != Some(&TokenType::IF) => self.open_body(next_kind, false),
+            _ => {}
+        }
+        self.previous = Some(kind.clone());
+    }
+
+    // After an `if`/`while`/`for` header or an `else`: a braced body stays
+    // on this line, anything else goes on the next one, indented.
+    fn open_body(&mut self, next: Option<&TokenType>, is_if: bool) {
+        self.in_statement = false;
+        if next != Some(&TokenType::LEFTBRACE) {
+            self.bodies.push((self.braces, is_if));
+            self.newline = true;
+        } else {
+            self.if_block = is_if;
+        }
+    }
+
+    // A statement ended, and with it any brace-less bodies it completed. A
+    // following `else` belongs to the innermost `if` whose branch just
+    // ended, so the bodies enclosing that `if` stay open until the `else`
+    // branch ends too.
+    fn end_statement(&mut self, next: Option<&TokenType>, closed_if_block: bool) {
+        self.newline = true;
+        self.in_statement = false;
+        let is_else = next == Some(&TokenType::ELSE);
+        if is_else && closed_if_block {
+            return;
+        }
+        while let Some(&(depth, is_if)) = self.bodies.last() {
+            if depth < self.braces {
+                break;
+            }
+            self.bodies.pop();
+            if is_if && is_else {
+                break;
+            }
+        }
+    }
+
+    fn space_before(&self, token: &Token) -> bool {
+        let Some(previous) = &self.previous else {
+            return false;
+        };
+        // Interpolated expressions hug the `${` and `}` around them.
+        if self.previous_unary || *previous == TokenType::INTERPOLATION {
+            return false;
+        }
+        match &token._type {
+            TokenType::STRING | TokenType::INTERPOLATION if token.lexeme.starts_with('}') => false,
+            TokenType::PLUSPLUS | TokenType::MINUSMINUS if self.previous_operand => false,
+            TokenType::SEMICOLON | TokenType::COMMA | TokenType::RIGHTPAREN | TokenType::DOT => false,
+            TokenType::RIGHTBRACE => *previous != TokenType::LEFTBRACE,
+            TokenType::LEFTPAREN => !self.previous_operand && *previous != TokenType::LEFTPAREN,
+            _ => !matches!(previous, TokenType::LEFTPAREN | TokenType::DOT),
+        }
+    }
+
+    fn comment(&mut self, comment: &Token, next: Option<&Token>) {
+        let trailing = !self.out.is_empty() && comment.start_line() == self.last_line;
+        if trailing {
+            self.out.push(' ');
+            self.out.push_str(&comment.lexeme);
+        } else {
+            self.newline = self.newline || !self.out.is_empty();
+            self.write(comment, false);
+        }
+        // Code may carry on after a block comment on the same line.
+        let inline = comment.lexeme.starts_with("/*") && next.is_some_and(|next| next.start_line() == comment.line);
+        self.newline = self.newline || !inline;
+        self.after_comment = inline;
+        self.last_line = comment.line;
+    }
+
+    fn write(&mut self, token: &Token, space: bool) {
+        if self.newline {
+            self.out.push('\n');
+            // Keep one blank line where the source had any, except just
+            // inside braces.
+            let after_brace = self.out.ends_with("{\n");
+            if token.start_line() > self.last_line + 1 && !after_brace && token._type != TokenType::RIGHTBRACE {
+                self.out.push('\n');
+            }
+            let continuation = self.parens > 0 || self.in_statement;
+            for _ in 0..self.braces + self.bodies.len() + usize::from(continuation) {
+                self.out.push_str(INDENT);
+            }
+            self.newline = false;
+        } else if space {
+            self.out.push(' ');
+        }
+        self.out.push_str(&token.lexeme);
+        self.last_line = token.line;
+    }
+
+    fn finish(mut self) -> String {
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        self.out
+    }
+}
+
+#[test]
+fn test_format_source() {
+    let source = "// Greeting.\nvar a=1;var  b = -a*(2+3);\n\n\nfun add(x,y){return x+y;} // Sum.\nif(a>b)print add(a,b);else{print !true;}\nfor(var i=0;i<3;i=i+1)\n  while (false) print i;\n{}\n";
+    let expected = "\
+// Greeting.
+var a = 1;
+var b = -a * (2 + 3);
+
+fun add(x, y) {
+  return x + y;
+} // Sum.
+if (a > b)
+  print add(a, b);
+else {
+  print !true;
+}
+for (var i = 0; i < 3; i = i + 1)
+  while (false)
+    print i;
+{}
+";
+    let formatted = format_source(source).unwrap();
+    assert_eq!(formatted, expected);
+    assert_eq!(format_source(&formatted).unwrap(), formatted);
+    let source = "if (a) if (b) x; else y;\nif (a) while (b) { x; } else y;\nif (a) if (b) { x; } else if (c) y; else z;\n";
+    let expected = "\
+if (a)
+  if (b)
+    x;
+  else
+    y;
+if (a)
+  while (b) {
+    x;
+  }
+else
+  y;
+if (a)
+  if (b) {
+    x;
+  } else if (c)
+    y;
+  else
+    z;
+";
+    let formatted = format_source(source).unwrap();
+    assert_eq!(formatted, expected);
+    assert_eq!(format_source(&formatted).unwrap(), formatted);
+    let source = "var a = 1 + // c\n2;\nfun f() {\n  print f(1, // x\n2);\n}\n";
+    let expected = "var a = 1 + // c\n  2;\nfun f() {\n  print f(1, // x\n    2);\n}\n";
+    assert_eq!(format_source(source).unwrap(), expected);
+    assert_eq!(format_source(expected).unwrap(), expected);
+    assert_eq!(format_source("print \"a ${ b+1 } c ${\"d\"}\";").unwrap(), "print \"a ${b + 1} c ${\"d\"}\";\n");
+    assert_eq!(format_source("print ~a**2|b<<1;").unwrap(), "print ~a ** 2 | b << 1;\n");
+    assert_eq!(format_source("i++;--j;k+=i++ - -1;print a?b:c;").unwrap(), "i++;\n--j;\nk += i++ - -1;\nprint a ? b : c;\n");
+    let source = "/* Header\n   /* nested */ */\nvar a = /* one */ 1; /* trailing */\n/// Adds.\n/// Twice.\nfun f() {}\n";
+    assert_eq!(format_source(source).unwrap(), source);
+    assert_eq!(format_source("print 1 +;").unwrap_err(), "ParseError at line 1 column 10: Expect expression.");
+}
@@ -0,0 +1,40 @@
+use std::io::{BufRead, Write};
+
+use serde_json::Value;
+
+// The base protocol shared by DAP and LSP: each JSON message is preceded by
+// a `Content-Length` header and a blank line.
+
+// Next message on the stream, or `None` once it ends or stops making sense.
+pub fn read_message(input: &mut dyn BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+pub fn write_message(output: &mut dyn Write, message: &Value) {
+    let body = message.to_string();
+    // Nothing can be reported once the client's stream is gone.
+    let _ = write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = output.flush();
+}
+
+#[cfg(test)]
+pub(crate) fn frame(message: Value) -> String {
+    let body = message.to_string();
+    format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+}
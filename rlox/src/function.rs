@@ -2,57 +2,68 @@ use std::rc::Rc;
 
 use crate::ast::{self, Function, LoxValue, StmtVisitable};
 use crate::errors::RuntimeReturn;
-use crate::interpreter::Interpreter;
+use crate::interpreter::{EvalResult, Interpreter};
+use crate::memory;
 
 pub trait LoxCallable: std::fmt::Display {
+    fn name(&self) -> &str;
     fn arity(&self) -> usize;
-    fn call(&self, interpreter: &Interpreter, arguments: Vec<Rc<LoxValue>>) -> Rc<LoxValue>;
+    fn call(&self, interpreter: &Interpreter, arguments: Vec<LoxValue>) -> EvalResult;
+    // The scope this callable captured, which the collector must keep alive.
+    fn closure(&self) -> Option<usize> {
+        None
+    }
 }
 
 // --------------------------------------------
 
 pub struct LoxFunction {
     declaration: Function,
+    closure: usize,
 }
 
 impl LoxFunction {
-    pub fn new(_declaration: Function) -> Self {
+    pub fn new(_declaration: Function, _closure: usize) -> Self {
         LoxFunction {
             declaration: _declaration,
+            closure: _closure,
         }
     }
 }
 
 impl LoxCallable for LoxFunction {
+    fn name(&self) -> &str {
+        &self.declaration.name.lexeme
+    }
     fn arity(&self) -> usize {
         self.declaration.params.len()
     }
-    fn call(&self, _interpreter: &Interpreter, _arguments: Vec<Rc<LoxValue>>) -> Rc<LoxValue> {
-        if _arguments.len() != self.arity() {
-            panic!("Expected {} arguments but got {}.", self.arity(), _arguments.len());
-        }
-        
-        _interpreter.environment.borrow_mut().enter_scope(false);
-        
+    fn closure(&self) -> Option<usize> {
+        Some(self.closure)
+    }
+    fn call(&self, _interpreter: &Interpreter, _arguments: Vec<LoxValue>) -> EvalResult {
+        _interpreter.check_budget()?;
+        let frame_size = self.declaration.params.len() * memory::binding_size();
+        _interpreter.allocate(memory::scope_size() + frame_size)?;
+
+        let previous = _interpreter.environment.borrow_mut().enter_closure(self.closure);
+
         let mut _arguments = _arguments;
-        let drain_arg = _arguments.drain(..).into_iter();
+        let drain_arg = _arguments.drain(..);
         for (param, arg) in self.declaration.params.iter().zip(drain_arg) {
-            _interpreter.environment.borrow_mut().define(&param.lexeme, arg);
+            _interpreter.environment.borrow_mut().define(param.symbol, arg);
         }
-        
+
         let result = <ast::Stmt as Clone>::clone(&self.declaration.body).accept(_interpreter);
 
-        _interpreter.environment.borrow_mut().exit_scope();
+        _interpreter.environment.borrow_mut().restore(previous);
 
         match result {
-            Err(err) => {
-                if let Some(ret) = err.downcast_ref::<RuntimeReturn>() {
-                    ret.0.clone()
-                } else {
-                    LoxValue::Nil.into()
-                }
-            }
-            _ => LoxValue::Nil.into()
+            Err(err) => match err.downcast::<RuntimeReturn>() {
+                Ok(ret) => Ok(ret.0),
+                Err(err) => Err(err),
+            },
+            _ => Ok(LoxValue::Nil),
         }
     }
 }
@@ -68,19 +79,22 @@ impl std::fmt::Display for LoxFunction {
 struct BuiltinFunctioClock;
 
 pub fn builtin_function_clock() -> LoxValue {
-    LoxValue::Callable(Box::new(BuiltinFunctioClock))
+    LoxValue::Callable(Rc::new(BuiltinFunctioClock))
 }
 
 impl LoxCallable for BuiltinFunctioClock {
+    fn name(&self) -> &str {
+        "clock"
+    }
     fn arity(&self) -> usize {
         0
     }
-    fn call(&self, _interpreter: &Interpreter, _arguments: Vec<Rc<LoxValue>>) -> Rc<LoxValue> {
+    fn call(&self, _interpreter: &Interpreter, _arguments: Vec<LoxValue>) -> EvalResult {
         let time = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
             .as_secs_f64();
-        LoxValue::Number(time).into()
+        Ok(LoxValue::Number(time))
     }
 }
 
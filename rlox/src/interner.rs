@@ -0,0 +1,95 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::{BuildHasherDefault, Hasher};
+use std::rc::Rc;
+
+// An interned identifier. Two symbols are equal exactly when their names
+// are, so scopes can key bindings by symbol and compare names as integers.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+// Identifier names, shared by every scanner and interpreter on the thread.
+// Only identifiers are interned here; string constants are shared per
+// scan by the scanner so untrusted scripts cannot grow this table with
+// arbitrary text.
+#[derive(Default)]
+struct Interner {
+    names: Vec<Rc<str>>,
+    symbols: HashMap<Rc<str>, Symbol>,
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::default());
+}
+
+pub fn intern(name: &str) -> Symbol {
+    INTERNER.with(|interner| {
+        let mut interner = interner.borrow_mut();
+        if let Some(symbol) = interner.symbols.get(name) {
+            return *symbol;
+        }
+        let symbol = Symbol(interner.names.len() as u32);
+        let name: Rc<str> = Rc::from(name);
+        interner.names.push(name.clone());
+        interner.symbols.insert(name, symbol);
+        symbol
+    })
+}
+
+impl Symbol {
+    pub fn as_str(&self) -> Rc<str> {
+        INTERNER.with(|interner| interner.borrow().names[self.0 as usize].clone())
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        intern(name)
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl Debug for Symbol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Symbol({:?})", self.as_str())
+    }
+}
+
+// Symbols are small sequential integers, so a multiplicative hash is
+// enough to spread them and much cheaper than the default SipHash.
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0.rotate_left(8) ^ *byte as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        }
+    }
+    fn write_u32(&mut self, n: u32) {
+        self.0 = (n as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    }
+}
+
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
+
+#[test]
+fn test_intern() {
+    let a = intern("counter");
+    let b = intern(&String::from("counter"));
+    let c = intern("count");
+
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+    assert_eq!(&*a.as_str(), "counter");
+    assert_eq!(a.to_string(), "counter");
+}
@@ -1,30 +1,48 @@
 use std::any::Any;
-use std::cell::RefCell;
+use std::cell::{Cell, Ref, RefCell};
+use std::cmp;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
 use super::ast::*;
 
 use crate::ast;
+use crate::coverage::Coverage;
+use crate::debugger::DebugHook;
 use crate::environment::Environment;
 
 use crate::function::builtin_function_clock;
 use crate::function::LoxFunction;
+use crate::interner::intern;
+use crate::memory::{self, GcStats, MemoryAccount, MemoryStats};
+use crate::module::{Module, ModuleLoader};
+use crate::parser::Parser;
+use crate::scanner::Scanner;
+use crate::scope_resolver::ScopeResolver;
+use crate::profiler::{Profile, Profiler};
 
 use super::token::Token;
 use super::token::TokenLiteral;
 use super::token::TokenType;
 
+use super::errors::InterruptKind;
 use super::errors::RuntimeError;
-use super::errors::RuntimeErrorT;
+use super::errors::RuntimeInterrupt;
 use super::errors::RuntimeReturn;
 
 type RuntimeResult = Result<(), Box<dyn Any>>;
+pub type EvalResult = Result<LoxValue, Box<dyn Any>>;
+// Receives each line a script prints.
+pub type PrintHandler = Box<dyn Fn(&str)>;
 use crate::impl_expr_visitable;
 use crate::impl_stmt_visitable;
 
 impl_expr_visitable! {
-    <Rc<LoxValue>>,
+    <EvalResult>,
     (Binary, binary),
     (Group, grouping),
     (Literal, literal),
@@ -33,6 +51,9 @@ impl_expr_visitable! {
     (Assign, assign),
     (Logical, logical),
     (Call, call),
+    (Update, update),
+    (Conditional, conditional),
+    (Get, get),
 }
 impl_stmt_visitable! {
     <RuntimeResult>,
@@ -44,216 +65,640 @@ impl_stmt_visitable! {
     (While, while),
     (Function, function),
     (Return, return),
+    (Import, import),
+    (Export, export),
 }
 
+// One active call: the callee's name and the token of the call site, whose
+// line is reported for the caller's frame in a stack trace.
+#[derive(Debug, Clone)]
+pub struct CallFrame {
+    pub function: String,
+    pub call_site: Token,
+}
+
+// Calls deeper than this raise "Stack overflow." unless configured otherwise.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+// Each Lox call recurses through several visitor frames on the native stack.
+// Calls run on a freshly allocated segment once less than `STACK_RED_ZONE`
+// bytes are left, so Lox recursion depth is bounded by `max_call_depth`
+// rather than by the size of the host thread's stack.
+const STACK_RED_ZONE: usize = 128 * 1024;
+const STACK_SEGMENT_SIZE: usize = 2 * 1024 * 1024;
+
+// A collection runs once this many bytes have been charged since the last
+// one, or twice what survived it, whichever is larger.
+const GC_MIN_THRESHOLD: usize = 1024 * 1024;
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+// The deadline is only compared against the clock every this many steps.
+const DEADLINE_CHECK_INTERVAL: u32 = 256;
+
 pub struct Interpreter {
     pub environment: RefCell<Environment>,
     pub locals: RefCell<HashMap<RcExpr, usize>>,
+    call_stack: RefCell<Vec<CallFrame>>,
+    max_call_depth: Cell<usize>,
+    // Execution budget, charged one step per loop iteration and per call.
+    fuel: Cell<Option<u64>>,
+    deadline: Cell<Option<Instant>>,
+    cancel_flag: RefCell<Option<Arc<AtomicBool>>>,
+    steps: Cell<u32>,
+    memory: MemoryAccount,
+    gc_stats: Cell<GcStats>,
+    gc_threshold: Cell<usize>,
+    // Collect on every allocation, to shake out missing roots in tests.
+    gc_stress: Cell<bool>,
+    profiler: RefCell<Option<Profiler>>,
+    coverage: RefCell<Option<Coverage>>,
+    debug_hook: RefCell<Option<Rc<dyn DebugHook>>>,
+    // Receives what `print` writes, instead of stdout.
+    print_handler: RefCell<Option<PrintHandler>>,
+    modules: RefCell<ModuleLoader>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Interpreter
 where
-    Self: ExprVisitor<Rc<LoxValue>> + StmtVisitor<RuntimeResult>,
+    Self: ExprVisitor<EvalResult> + StmtVisitor<RuntimeResult>,
 {
     pub fn new() -> Self {
         let mut env = Environment::new();
-        env.define("clock", builtin_function_clock().into());
+        env.define_native(intern("clock"), builtin_function_clock());
         Interpreter {
             environment: RefCell::new(env),
             locals: HashMap::new().into(),
+            call_stack: RefCell::new(Vec::new()),
+            max_call_depth: Cell::new(DEFAULT_MAX_CALL_DEPTH),
+            fuel: Cell::new(None),
+            deadline: Cell::new(None),
+            cancel_flag: RefCell::new(None),
+            steps: Cell::new(0),
+            memory: MemoryAccount::new(),
+            gc_stats: Cell::new(GcStats::default()),
+            gc_threshold: Cell::new(GC_MIN_THRESHOLD),
+            gc_stress: Cell::new(false),
+            profiler: RefCell::new(None),
+            coverage: RefCell::new(None),
+            debug_hook: RefCell::new(None),
+            print_handler: RefCell::new(None),
+            modules: RefCell::new(ModuleLoader::default()),
+        }
+    }
+    pub fn set_max_call_depth(&self, depth: usize) {
+        self.max_call_depth.set(depth);
+    }
+    pub fn max_call_depth(&self) -> usize {
+        self.max_call_depth.get()
+    }
+    // Limits the number of loop iterations and calls the script may make.
+    // `None` removes the limit.
+    pub fn set_fuel(&self, fuel: Option<u64>) {
+        self.fuel.set(fuel);
+    }
+    pub fn remaining_fuel(&self) -> Option<u64> {
+        self.fuel.get()
+    }
+    pub fn set_deadline(&self, deadline: Option<Instant>) {
+        self.deadline.set(deadline);
+    }
+    // Execution stops at the next loop iteration or call once the flag is
+    // set, which may happen from any thread.
+    pub fn set_cancel_flag(&self, flag: Option<Arc<AtomicBool>>) {
+        *self.cancel_flag.borrow_mut() = flag;
+    }
+    // Caps the bytes the script may hold in strings, scopes, bindings and
+    // functions. Allocations beyond the cap raise "Out of memory.".
+    pub fn set_memory_limit(&self, limit: Option<usize>) {
+        self.memory.set_limit(limit);
+    }
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.memory.stats()
+    }
+    // Charges an allocation, collecting garbage first when enough has been
+    // allocated since the last collection, and again before giving up on
+    // the memory limit. Callers must not hold the only reference to a
+    // closure on the Rust stack across this call; see `Environment::push_root`.
+    pub fn allocate(&self, bytes: usize) -> Result<(), RuntimeError> {
+        if self.gc_stress.get() || self.memory.since_settled() + bytes > self.gc_threshold.get() {
+            self.collect_garbage();
+        }
+        if self.memory.charge(bytes).is_ok() {
+            return Ok(());
+        }
+        self.collect_garbage();
+        self.memory.charge(bytes)
+    }
+    pub fn collect_garbage(&self) {
+        let collection = self.environment.borrow_mut().collect();
+        let released = self.memory.settle(collection.reachable_bytes);
+        let mut stats = self.gc_stats.get();
+        stats.collections += 1;
+        stats.scopes_freed += collection.scopes_freed;
+        stats.bytes_reclaimed += released;
+        stats.live_scopes = collection.live_scopes;
+        self.gc_stats.set(stats);
+        self.gc_threshold.set(GC_MIN_THRESHOLD.max(collection.reachable_bytes * GC_HEAP_GROW_FACTOR));
+    }
+    pub fn gc_stats(&self) -> GcStats {
+        self.gc_stats.get()
+    }
+    pub fn set_gc_stress(&self, stress: bool) {
+        self.gc_stress.set(stress);
+    }
+    // Charges one step against the execution budget. Called on every loop
+    // iteration and at the start of every function call.
+    pub fn check_budget(&self) -> RuntimeResult {
+        if let Some(fuel) = self.fuel.get() {
+            if fuel == 0 {
+                return Err(RuntimeInterrupt::new(InterruptKind::OutOfFuel).into());
+            }
+            self.fuel.set(Some(fuel - 1));
+        }
+        if let Some(flag) = self.cancel_flag.borrow().as_ref() {
+            if flag.load(Ordering::Relaxed) {
+                return Err(RuntimeInterrupt::new(InterruptKind::Cancelled).into());
+            }
+        }
+        if let Some(deadline) = self.deadline.get() {
+            let steps = self.steps.get().wrapping_add(1);
+            self.steps.set(steps);
+            if steps.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= deadline {
+                return Err(RuntimeInterrupt::new(InterruptKind::DeadlineExceeded).into());
+            }
         }
+        Ok(())
     }
     pub fn interpret(&self, stmts: &Vec<RcStmt>) -> RuntimeResult {
         for stmt in stmts {
-            self.execute(stmt.clone())?;
+            self.execute(stmt.clone()).map_err(|err| self.attach_trace(err))?;
         }
         Ok(())
     }
-    pub fn resolve(&mut self, expr: RcExpr, depth: usize) {
-        self.locals.borrow_mut().insert(expr, depth);
+    // Records time and counts per function and per line until
+    // `finish_profiling`.
+    pub fn start_profiling(&self) {
+        *self.profiler.borrow_mut() = Some(Profiler::new());
     }
-    fn lookup_variable(&self, name: &Token, expr: &RcExpr) -> Rc<LoxValue> {
-        let distance = self.locals.borrow();
-        if let Some(distance) = distance.get(expr) {
-            return self.environment.borrow().get_at(*distance, &name.lexeme).unwrap();
-        } else {
-            return self.environment.borrow().get(&name.lexeme).unwrap();
+    pub fn finish_profiling(&self) -> Option<Profile> {
+        let mut profile = self.profiler.borrow_mut().take().map(Profiler::finish)?;
+        let files: Vec<usize> = profile.lines.keys().map(|&(file, _)| file).filter(|&file| file > 0).collect();
+        for file in files {
+            if let Some(path) = self.file_path(file) {
+                profile.files.insert(file, path);
+            }
         }
+        Some(profile)
     }
-    fn execute(&self, stmt: RcStmt) -> RuntimeResult {
-        <ast::Stmt as Clone>::clone(&stmt).accept(self)
+    fn profile(&self, record: impl FnOnce(&mut Profiler)) {
+        if let Some(profiler) = self.profiler.borrow_mut().as_mut() {
+            record(profiler);
+        }
     }
-    fn evaluate(&self, expr: RcExpr) -> Rc<LoxValue> {
-        <ast::Expr as Clone>::clone(&expr).accept(self)
+    // Records which statement lines run until `finish_coverage`. Scripts
+    // are registered with `cover` before they are interpreted; imported
+    // modules register themselves as they load.
+    pub fn start_coverage(&self) {
+        *self.coverage.borrow_mut() = Some(Coverage::new());
     }
-}
+    pub fn cover(&self, file: usize, path: &str, source: &str, stmts: &[RcStmt]) {
+        if let Some(coverage) = self.coverage.borrow_mut().as_mut() {
+            coverage.begin_file(file, path, source, stmts);
+        }
+    }
+    pub fn finish_coverage(&self) -> Option<Coverage> {
+        self.coverage.borrow_mut().take()
+    }
+    // Installs a debugger that is consulted before every statement.
+    pub fn set_debug_hook(&self, hook: Option<Rc<dyn DebugHook>>) {
+        *self.debug_hook.borrow_mut() = hook;
+    }
+    pub fn set_print_handler(&self, handler: Option<PrintHandler>) {
+        *self.print_handler.borrow_mut() = handler;
+    }
+    // Where the tokens of `file` came from, if known. The script is file 0.
+    pub fn file_path(&self, file: usize) -> Option<String> {
+        self.modules.borrow().path(file).map(|path| path.display().to_string())
+    }
+    // The file being run, which the script's own imports are relative to.
+    pub fn set_script_path(&self, path: &Path) {
+        self.modules.borrow_mut().set_script(path);
+    }
+    // Directories searched for modules not found next to the importing
+    // file.
+    pub fn set_module_path(&self, dirs: Vec<PathBuf>) {
+        self.modules.borrow_mut().search_path = dirs;
+    }
+    // The module `stmt` names, evaluated on first import and cached after.
+    fn import(&self, stmt: &Import) -> Result<Rc<Module>, Box<dyn Any>> {
+        let path = stmt.path.literal.to_string();
+        let located = self.modules.borrow().locate(&path, stmt.path.file);
+        let Some(located) = located else {
+            return Err(RuntimeError::new(&stmt.path, &format!("Can't find module '{}'.", path)).into());
+        };
+        if let Some(module) = self.modules.borrow().cached(&located) {
+            return Ok(module);
+        }
+        let file = self.modules.borrow_mut().begin(&located).map_err(|message| RuntimeError::new(&stmt.path, &message))?;
+        let result = self.run_module(stmt, &located, file);
+        self.modules.borrow_mut().end(result.as_ref().ok().cloned());
+        result
+    }
+    fn run_module(&self, stmt: &Import, located: &Path, file: usize) -> Result<Rc<Module>, Box<dyn Any>> {
+        let name = located.display().to_string();
+        let fail = |message: &str| RuntimeError::new(&stmt.path, &format!("In module '{}': {}", name, message));
+        let source = std::fs::read_to_string(located).map_err(|err| fail(&err.to_string()))?;
+        let (tokens, errors) = Scanner::build(&source).in_file(file).scan();
+        if let Some(error) = errors.first() {
+            return Err(fail(&error.to_string()).into());
+        }
+        let stmts = Parser::new(tokens).parse().map_err(|err| fail(&err.0))?;
+        let resolver = ScopeResolver::new(self);
+        resolver.resolve(&stmts);
+        if let Some(error) = resolver.finish().errors.first() {
+            return Err(fail(&error.to_string()).into());
+        }
 
-impl ExprVisitor<Rc<LoxValue>> for Interpreter {
-    fn visit_binary(&self, expr: &Binary) -> Rc<LoxValue> {
-        let binding = self.evaluate(expr.left.clone());
-        let left = binding.as_ref();
-        let binding = self.evaluate(expr.right.clone());
-        let right = binding.as_ref();
+        self.cover(file, &name, &source, &stmts);
+        let scope = self.environment.borrow_mut().module_scope();
+        let module = Rc::new(Module::new(name.clone(), scope, &stmts));
+        let previous = self.environment.borrow_mut().switch_to(scope);
+        let result = stmts.iter().try_for_each(|stmt| self.execute(stmt.clone()));
+        self.environment.borrow_mut().switch_back(previous);
+        result?;
+        Ok(module)
+    }
+    pub fn resolve(&self, expr: RcExpr, depth: usize) {
+        self.locals.borrow_mut().insert(expr, depth);
+    }
+    // Active frames, outermost first. Natives get this through the
+    // `&Interpreter` they are called with.
+    pub fn call_stack(&self) -> Ref<'_, Vec<CallFrame>> {
+        self.call_stack.borrow()
+    }
+    // Renders the current call stack innermost first, starting from `line`
+    // in the innermost frame and ending with the top-level script. Runs of
+    // identical entries, as left by deep recursion, are folded into one.
+    pub fn stack_trace(&self, line: usize) -> Vec<String> {
+        let mut entries = vec![];
+        let mut line = line;
+        for frame in self.call_stack.borrow().iter().rev() {
+            entries.push(format!("[line {}] in {}()", line, frame.function));
+            line = frame.call_site.line;
+        }
+        entries.push(format!("[line {}] in script", line));
 
-        let ret = match expr.operator._type {
-            TokenType::GREATER => {
-                let (left, right) = check_number_operands(&expr.operator, &left, &right).unwrap();
-                LoxValue::Bool(left > right)
+        let mut trace: Vec<String> = vec![];
+        let mut repeated = 0;
+        for (i, entry) in entries.iter().enumerate() {
+            if i > 0 && entries[i - 1] == *entry {
+                repeated += 1;
+                continue;
             }
-            TokenType::GREATEREQUAL => {
-                let (left, right) = check_number_operands(&expr.operator, &left, &right).unwrap();
-                LoxValue::Bool(left >= right)
+            if repeated > 0 {
+                trace.push(format!("[previous line repeated {} more times]", repeated));
+                repeated = 0;
             }
-            TokenType::LESS => {
-                let (left, right) = check_number_operands(&expr.operator, &left, &right).unwrap();
-                LoxValue::Bool(left < right)
-            }
-            TokenType::LESSEQUAL => {
-                let (left, right) = check_number_operands(&expr.operator, &left, &right).unwrap();
-                LoxValue::Bool(left <= right)
+            trace.push(entry.clone());
+        }
+        trace
+    }
+    // Records the stack trace on a runtime error the first time it passes a
+    // frame boundary, while the frames it was raised in are still active.
+    fn attach_trace(&self, mut err: Box<dyn Any>) -> Box<dyn Any> {
+        if let Some(err) = err.downcast_mut::<RuntimeError>() {
+            if err.trace.is_empty() {
+                let line = match (err.line(), self.call_stack.borrow().last()) {
+                    (Some(line), _) => line,
+                    (None, Some(frame)) => frame.call_site.line,
+                    (None, None) => 0,
+                };
+                err.trace = self.stack_trace(line);
             }
-            TokenType::BANGEQUAL => LoxValue::Bool(!is_equal(&left, &right)),
-            TokenType::EQUALEQUAL => LoxValue::Bool(is_equal(&left, &right)),
-            TokenType::MINUS => {
-                let (left, right) = check_number_operands(&expr.operator, &left, &right).unwrap();
-                LoxValue::Number(left - right)
+        }
+        err
+    }
+    fn lookup_variable(&self, name: &Token, expr: &RcExpr) -> EvalResult {
+        let distance = self.locals.borrow();
+        let value = if let Some(distance) = distance.get(expr) {
+            self.environment.borrow().get_at(*distance, name.symbol)
+        } else {
+            self.environment.borrow().get(name.symbol)
+        };
+        value.map_err(|err| RuntimeError::new(name, &err.message).into())
+    }
+    fn assign_variable(&self, name: &Token, expr: &RcExpr, value: LoxValue) -> Result<(), RuntimeError> {
+        let assigned = if let Some(distance) = self.locals.borrow().get(expr) {
+            self.environment.borrow_mut().assign_at(*distance, name.symbol, value)
+        } else {
+            self.environment.borrow_mut().assign(name.symbol, value)
+        };
+        assigned.map_err(|err| RuntimeError::new(name, &err.message))
+    }
+    fn call_value(&self, callee: &LoxValue, paren: &Token, arguments: Vec<LoxValue>) -> EvalResult {
+        let LoxValue::Callable(callee) = callee else {
+            return Err(RuntimeError::new(paren, "Can only call functions and classes.").into());
+        };
+        if arguments.len() != callee.arity() {
+            let err = format!("Expected {} arguments but got {}.", callee.arity(), arguments.len());
+            return Err(RuntimeError::new(paren, &err).into());
+        }
+        if self.call_stack.borrow().len() >= self.max_call_depth.get() {
+            return Err(RuntimeError::new(paren, "Stack overflow.").into());
+        }
+
+        self.call_stack.borrow_mut().push(CallFrame {
+            function: callee.name().to_string(),
+            call_site: paren.clone(),
+        });
+        self.profile(|profiler| profiler.enter_function(callee.name()));
+        let result = stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT_SIZE, || {
+            callee.call(self, arguments)
+        });
+        self.profile(|profiler| profiler.exit_function());
+        let result = result.map_err(|err| self.attach_trace(err));
+        self.call_stack.borrow_mut().pop();
+        result
+    }
+    fn execute(&self, stmt: RcStmt) -> RuntimeResult {
+        // (file, line) the statement starts on; blocks have none.
+        let location = stmt.line().and(stmt.token()).map(|token| (token.file, token.line));
+        if let (Some((file, line)), Some(coverage)) = (location, self.coverage.borrow_mut().as_mut()) {
+            coverage.hit(file, line);
+        }
+        if let Some((file, line)) = location {
+            // Cloned out so the hook can evaluate expressions re-entrantly.
+            let hook = self.debug_hook.borrow().clone();
+            if let Some(hook) = hook {
+                hook.before_statement(self, file, line)?;
             }
+        }
+        let location = if self.profiler.borrow().is_some() { location } else { None };
+        let Some((file, line)) = location else {
+            return <ast::Stmt as Clone>::clone(&stmt).accept(self);
+        };
+        self.profile(|profiler| profiler.enter_line(file, line));
+        let result = <ast::Stmt as Clone>::clone(&stmt).accept(self);
+        self.profile(|profiler| profiler.exit_line());
+        result
+    }
+    // The arithmetic, comparison and bitwise operators, also used by
+    // compound assignment.
+    fn binary_operation(&self, operator: &Token, kind: &TokenType, left: &LoxValue, right: &LoxValue) -> EvalResult {
+        let ret = match kind {
+            TokenType::GREATER => compare_numbers(operator, left, right, cmp::Ordering::is_gt)?,
+            TokenType::GREATEREQUAL => compare_numbers(operator, left, right, cmp::Ordering::is_ge)?,
+            TokenType::LESS => compare_numbers(operator, left, right, cmp::Ordering::is_lt)?,
+            TokenType::LESSEQUAL => compare_numbers(operator, left, right, cmp::Ordering::is_le)?,
+            TokenType::BANGEQUAL => LoxValue::Bool(!is_equal(left, right)),
+            TokenType::EQUALEQUAL => LoxValue::Bool(is_equal(left, right)),
+            TokenType::MINUS => arithmetic(operator, left, right, i64::checked_sub, |a, b| a - b)?,
             TokenType::PLUS => {
-                if let (LoxValue::Number(left), LoxValue::Number(right)) = (&left, &right) {
-                    LoxValue::Number(left + right)
-                } else if let (LoxValue::String(left), LoxValue::String(right)) = (&left, &right) {
-                    LoxValue::String(left.to_string() + &right.to_string())
+                if let (LoxValue::String(left), LoxValue::String(right)) = (left, right) {
+                    let concatenated: Rc<str> = [left.as_ref(), right.as_ref()].concat().into();
+                    self.allocate(memory::string_size(&concatenated))
+                        .map_err(|err| RuntimeError::new(operator, &err.message))?;
+                    LoxValue::String(concatenated)
+                } else if as_number(left).is_some() && as_number(right).is_some() {
+                    arithmetic(operator, left, right, i64::checked_add, |a, b| a + b)?
                 } else {
-                    panic!("Operands must be two numbers or two strings.");
+                    return Err(RuntimeError::new(operator, "Operands must be two numbers or two strings.").into());
                 }
             }
+            // Always a float, as in the book; `~/` divides integers.
             TokenType::SLASH => {
-                let (left, right) = check_number_operands(&expr.operator, &left, &right).unwrap();
+                let (left, right) = check_number_operands(operator, left, right)?;
                 LoxValue::Number(left / right)
             }
-            TokenType::STAR => {
-                let (left, right) = check_number_operands(&expr.operator, &left, &right).unwrap();
-                LoxValue::Number(left * right)
+            TokenType::STAR => arithmetic(operator, left, right, i64::checked_mul, |a, b| a * b)?,
+            TokenType::PERCENT => {
+                check_divisor(operator, right)?;
+                arithmetic(operator, left, right, i64::checked_rem, |a, b| a % b)?
+            }
+            TokenType::TILDESLASH => {
+                check_divisor(operator, right)?;
+                match (left, right) {
+                    (LoxValue::Int(left), LoxValue::Int(right)) => integer(operator, left.checked_div(*right))?,
+                    _ => {
+                        let (left, right) = check_number_operands(operator, left, right)?;
+                        // The result is an integer, so a float zero has no answer either.
+                        if right == 0.0 {
+                            return Err(RuntimeError::new(operator, "Division by zero.").into());
+                        }
+                        integer(operator, whole_number((left / right).trunc()))?
+                    }
+                }
+            }
+            TokenType::STARSTAR => match (left, right) {
+                (LoxValue::Int(base), LoxValue::Int(exponent)) if *exponent >= 0 => {
+                    let exponent = u32::try_from(*exponent).ok();
+                    integer(operator, exponent.and_then(|exponent| base.checked_pow(exponent)))?
+                }
+                _ => {
+                    let (base, exponent) = check_number_operands(operator, left, right)?;
+                    LoxValue::Number(base.powf(exponent))
+                }
+            },
+            TokenType::AMPERSAND => bitwise(operator, left, right, |a, b| Some(a & b))?,
+            TokenType::PIPE => bitwise(operator, left, right, |a, b| Some(a | b))?,
+            TokenType::CARET => bitwise(operator, left, right, |a, b| Some(a ^ b))?,
+            // Bits shifted out are dropped; only the shift amount is checked.
+            TokenType::LESSLESS => {
+                bitwise(operator, left, right, |a, b| u32::try_from(b).ok().and_then(|b| a.checked_shl(b)))?
+            }
+            TokenType::GREATERGREATER => {
+                bitwise(operator, left, right, |a, b| u32::try_from(b).ok().and_then(|b| a.checked_shr(b)))?
             }
             _ => {
                 panic!("Unknown operator.");
             }
         };
-        return ret.into();
-        // Unreachable.
+        Ok(ret)
     }
-    fn visit_grouping(&self, expr: &Group) -> Rc<LoxValue> {
+    pub(crate) fn evaluate(&self, expr: RcExpr) -> EvalResult {
+        <ast::Expr as Clone>::clone(&expr).accept(self)
+    }
+}
+
+impl ExprVisitor<EvalResult> for Interpreter {
+    fn visit_binary(&self, expr: &Binary) -> EvalResult {
+        let left = &self.evaluate(expr.left.clone())?;
+        let right = &self.evaluate(expr.right.clone())?;
+        self.binary_operation(&expr.operator, &expr.operator._type, left, right)
+    }
+
+    fn visit_grouping(&self, expr: &Group) -> EvalResult {
         self.evaluate(expr.expression.clone())
     }
-    fn visit_literal(&self, expr: &Literal) -> Rc<LoxValue> {
+    fn visit_literal(&self, expr: &Literal) -> EvalResult {
         let ret = match expr.value.clone() {
+            TokenLiteral::Int(value) => LoxValue::Int(value),
             TokenLiteral::Number(value) => LoxValue::Number(value),
+            // Constants are shared with the AST, so evaluating one allocates nothing.
             TokenLiteral::String(value) => LoxValue::String(value),
             TokenLiteral::Bool(value) => LoxValue::Bool(value),
             TokenLiteral::Nil => LoxValue::Nil,
         };
-        return ret.into();
+        Ok(ret)
     }
-    fn visit_unary(&self, expr: &Unary) -> Rc<LoxValue> {
-        let right = self.evaluate(expr.right.clone());
+    fn visit_unary(&self, expr: &Unary) -> EvalResult {
+        let right = self.evaluate(expr.right.clone())?;
         let ret = match expr.operator._type {
             TokenType::BANG => {
                 let result = !is_truthy(&right);
                 LoxValue::Bool(result)
             }
-            TokenType::MINUS => {
-                let right = check_number_operand(&expr.operator, &right).unwrap();
-                LoxValue::Number(-right)
-            }
+            TokenType::MINUS => match right {
+                LoxValue::Int(right) => integer(&expr.operator, right.checked_neg())?,
+                right => LoxValue::Number(-check_number_operand(&expr.operator, &right)?),
+            },
+            TokenType::TILDE => LoxValue::Int(!check_integer_operand(&expr.operator, &right)?),
+            TokenType::STRINGIFY => match right {
+                LoxValue::String(_) => right,
+                value => {
+                    let text: Rc<str> = value.to_string().into();
+                    self.allocate(memory::string_size(&text))
+                        .map_err(|err| RuntimeError::new(&expr.operator, &err.message))?;
+                    LoxValue::String(text)
+                }
+            },
             _ => {
                 panic!("Unknown operator.");
             }
         };
-        return ret.into();
+        Ok(ret)
     }
 
-    fn visit_variable(&self, expr: &Variable) -> Rc<LoxValue> {
+    fn visit_variable(&self, expr: &Variable) -> EvalResult {
         // 11.4
         // return self.environment.borrow().get(&expr.name.lexeme).unwrap();
         let rcexpr = Rc::new(ast::Expr::Variable(expr.clone()));
-        return self.lookup_variable(&expr.name, &rcexpr);
+        self.lookup_variable(&expr.name, &rcexpr)
     }
 
-    fn visit_assign(&self, expr: &Assign) -> Rc<LoxValue> {
-        let value = self.evaluate(expr.value.clone());
+    fn visit_assign(&self, expr: &Assign) -> EvalResult {
+        let value = self.evaluate(expr.value.clone())?;
         // 11.4
         // self.environment.borrow_mut().assign(&stmt.name.lexeme, value.clone()).unwrap();
         let rcexpr = Rc::new(ast::Expr::Assign(expr.clone()));
-        if let Some(distance) = self.locals.borrow().get(&rcexpr) {
-            self.environment.borrow_mut().assign_at(*distance, &expr.name.lexeme, value.clone()).unwrap();
+        self.assign_variable(&expr.name, &rcexpr, value.clone())?;
+        Ok(value)
+    }
+
+    fn visit_update(&self, expr: &Update) -> EvalResult {
+        let rcexpr = Rc::new(ast::Expr::Update(expr.clone()));
+        let old = self.lookup_variable(&expr.name, &rcexpr)?;
+        let value = self.evaluate(expr.value.clone())?;
+        let kind = match expr.operator._type {
+            TokenType::PLUSPLUS | TokenType::PLUSEQUAL => TokenType::PLUS,
+            TokenType::MINUSMINUS | TokenType::MINUSEQUAL => TokenType::MINUS,
+            TokenType::STAREQUAL => TokenType::STAR,
+            TokenType::SLASHEQUAL => TokenType::SLASH,
+            TokenType::PERCENTEQUAL => TokenType::PERCENT,
+            _ => panic!("Unknown operator."),
+        };
+        let new = self.binary_operation(&expr.operator, &kind, &old, &value)?;
+        self.assign_variable(&expr.name, &rcexpr, new.clone())?;
+        Ok(if expr.postfix { old } else { new })
+    }
+
+    fn visit_conditional(&self, expr: &Conditional) -> EvalResult {
+        if is_truthy(&self.evaluate(expr.condition.clone())?) {
+            self.evaluate(expr.then_branch.clone())
         } else {
-            self.environment.borrow_mut().assign(&expr.name.lexeme, value.clone()).unwrap();
+            self.evaluate(expr.else_branch.clone())
         }
-        return value.clone();
     }
 
-    fn visit_logical(&self, stmt: &Logical) -> Rc<LoxValue> {
-        let left = self.evaluate(stmt.left.clone());
+    fn visit_get(&self, expr: &Get) -> EvalResult {
+        let LoxValue::Module(module) = self.evaluate(expr.object.clone())? else {
+            return Err(RuntimeError::new(&expr.name, "Only modules have properties.").into());
+        };
+        let value = module.get(&self.environment.borrow(), expr.name.symbol);
+        value.ok_or_else(|| {
+            let message = format!("Module '{}' has no export '{}'.", module.name, expr.name.lexeme);
+            RuntimeError::new(&expr.name, &message).into()
+        })
+    }
+
+    fn visit_logical(&self, stmt: &Logical) -> EvalResult {
+        let left = self.evaluate(stmt.left.clone())?;
         if stmt.operator._type == TokenType::OR {
             if is_truthy(&left) {
-                return left;
-            }
-        } else {
-            if !is_truthy(&left) {
-                return left;
+                return Ok(left);
             }
+        } else if !is_truthy(&left) {
+            return Ok(left);
         }
-        return self.evaluate(stmt.right.clone());
+        self.evaluate(stmt.right.clone())
     }
 
-    fn visit_call(&self, stmt: &Call) -> Rc<LoxValue> {
-        let callee = self.evaluate(stmt.callee.clone());
+    fn visit_call(&self, stmt: &Call) -> EvalResult {
+        let callee = self.evaluate(stmt.callee.clone())?;
+        // The callee and the arguments evaluated so far stay rooted while
+        // the remaining arguments run, since those may trigger a collection.
+        self.environment.borrow_mut().push_root(callee.clone());
         let mut arguments = Vec::new();
-
-        for argument in stmt.arguments.clone() {
-            let arg = self.evaluate(argument);
+        let evaluated = stmt.arguments.iter().try_for_each(|argument| {
+            let arg = self.evaluate(argument.clone())?;
+            self.environment.borrow_mut().push_root(arg.clone());
             arguments.push(arg);
-        }
-        if let LoxValue::Callable(callee) = callee.as_ref() {
-            return callee.call(self, arguments).into();
-        }
-        panic!("Can only call functions and classes.");
+            Ok(())
+        });
+        let rooted = 1 + arguments.len();
+        let result = evaluated.and_then(|_| self.call_value(&callee, &stmt.paren, arguments));
+        self.environment.borrow_mut().pop_roots(rooted);
+        result
     }
 }
 
 impl StmtVisitor<RuntimeResult> for Interpreter {
     fn visit_expression(&self, stmt: &Expression) -> RuntimeResult {
-        self.evaluate(stmt.expression.clone());
+        self.evaluate(stmt.expression.clone())?;
         Ok(())
     }
     fn visit_print(&self, stmt: &Print) -> RuntimeResult {
-        let value = self.evaluate(stmt.expression.clone());
-        println!("{}", value);
+        let value = self.evaluate(stmt.expression.clone())?;
+        match self.print_handler.borrow().as_ref() {
+            Some(handler) => handler(&value.to_string()),
+            None => println!("{}", value),
+        }
         Ok(())
     }
     fn visit_var(&self, stmt: &Var) -> RuntimeResult {
+        // Charged up front: the initializer's value is not rooted yet.
+        self.allocate(memory::binding_size())
+            .map_err(|err| RuntimeError::new(&stmt.name, &err.message))?;
         let value = if let Some(initializer) = &stmt.initializer {
-            self.evaluate(initializer.clone())
+            self.evaluate(initializer.clone())?
         } else {
-            Rc::new(LoxValue::Nil)
+            LoxValue::Nil
         };
-        self.environment
-            .borrow_mut()
-            .define(&stmt.name.lexeme, value.into());
+        let mut environment = self.environment.borrow_mut();
+        if stmt.constant {
+            environment.define_constant(stmt.name.symbol, value);
+        } else {
+            environment.define(stmt.name.symbol, value);
+        }
         Ok(())
     }
 
     fn visit_block(&self, stmt: &Block) -> RuntimeResult {
+        self.allocate(memory::scope_size())?;
         self.environment.borrow_mut().enter_scope(false);
-        for statement in &stmt.statements {
-            self.execute(statement.clone())?;
-        }
+        let result = stmt.statements.iter().try_for_each(|statement| self.execute(statement.clone()));
         self.environment.borrow_mut().exit_scope();
-        Ok(())
+        result
     }
 
     fn visit_if(&self, stmt: &If) -> RuntimeResult {
-        if is_truthy(&self.evaluate(stmt.condition.clone())) {
+        if is_truthy(&self.evaluate(stmt.condition.clone())?) {
             self.execute(stmt.then_branch.clone())?;
         } else if let Some(else_branch) = &stmt.else_branch {
             self.execute(else_branch.clone())?;
@@ -262,54 +707,152 @@ impl StmtVisitor<RuntimeResult> for Interpreter {
     }
 
     fn visit_while(&self, stmt: &While) -> RuntimeResult {
-        while is_truthy(&self.evaluate(stmt.condition.clone())) {
+        while is_truthy(&self.evaluate(stmt.condition.clone())?) {
+            self.check_budget()?;
             self.execute(stmt.body.clone())?;
         }
         Ok(())
     }
 
     fn visit_function(&self, stmt: &Function) -> RuntimeResult {
-        let function_name = stmt.name.lexeme.clone();
-        let function = LoxValue::Callable(Box::new(LoxFunction::new(stmt.clone())));
+        self.allocate(memory::function_size() + memory::binding_size())
+            .map_err(|err| RuntimeError::new(&stmt.name, &err.message))?;
+        let closure = self.environment.borrow().current();
+        let function = LoxValue::Callable(Rc::new(LoxFunction::new(stmt.clone(), closure)));
         self.environment
             .borrow_mut()
-            .define(&function_name, function.into());
+            .define(stmt.name.symbol, function);
         Ok(())
     }
 
     fn visit_return(&self, stmt: &Return) -> RuntimeResult {
         let ret = if let Some(value) = &stmt.value {
-            self.evaluate(value.clone())
+            self.evaluate(value.clone())?
         } else {
-            Rc::new(LoxValue::Nil)
+            LoxValue::Nil
         };
         Err(Box::new(RuntimeReturn::new(ret)))
     }
+
+    fn visit_import(&self, stmt: &Import) -> RuntimeResult {
+        let module = self.import(stmt)?;
+        self.allocate(memory::binding_size())
+            .map_err(|err| RuntimeError::new(&stmt.name, &err.message))?;
+        self.environment.borrow_mut().define(stmt.name.symbol, LoxValue::Module(module));
+        Ok(())
+    }
+
+    // Which declarations are exported is read off the syntax tree when the
+    // module is loaded; running one is running its declaration, which is
+    // not a statement of its own to hooks and coverage.
+    fn visit_export(&self, stmt: &Export) -> RuntimeResult {
+        <ast::Stmt as Clone>::clone(&stmt.declaration).accept(self)
+    }
 }
 
 // ----------------------------------------------------------------
 // ----------------------------------------------------------------
 
+fn as_number(value: &LoxValue) -> Option<f64> {
+    match *value {
+        LoxValue::Int(v) => Some(v as f64),
+        LoxValue::Number(v) => Some(v),
+        _ => None,
+    }
+}
+
+// Both operands as floats, integers converted.
 fn check_number_operands(
     operator: &Token,
     left: &LoxValue,
     right: &LoxValue,
 ) -> Result<(f64, f64), RuntimeError> {
-    if let (LoxValue::Number(l), LoxValue::Number(r)) = (left, right) {
-        return Ok((*l, *r));
+    if let (Some(l), Some(r)) = (as_number(left), as_number(right)) {
+        return Ok((l, r));
     }
     let err = format!("Operands must be numbers. Got {}, {}", left, right);
     Err(RuntimeError::new(operator, &err))
 }
 
 fn check_number_operand(operator: &Token, operand: &LoxValue) -> Result<f64, RuntimeError> {
-    if let &LoxValue::Number(v) = operand {
+    if let Some(v) = as_number(operand) {
         return Ok(v);
     }
     let err = format!("Operand must be a number. Got {}", operand);
     Err(RuntimeError::new(operator, &err))
 }
 
+// `v` as an integer if it is one and in range: not fractional, infinite,
+// NaN or beyond ±2^63.
+fn whole_number(v: f64) -> Option<i64> {
+    let in_range = (-9.223372036854776e18..9.223372036854776e18).contains(&v);
+    (in_range && v.fract() == 0.0).then_some(v as i64)
+}
+
+// Floats are accepted where they hold a whole number, e.g. `4.0`.
+fn check_integer_operand(operator: &Token, operand: &LoxValue) -> Result<i64, RuntimeError> {
+    match *operand {
+        LoxValue::Int(v) => Ok(v),
+        LoxValue::Number(v) if whole_number(v).is_some() => Ok(v as i64),
+        _ => Err(RuntimeError::new(operator, &format!("Operand must be an integer. Got {}", operand))),
+    }
+}
+
+// `op` returns `None` for a shift amount outside 0..64.
+fn bitwise(
+    operator: &Token,
+    left: &LoxValue,
+    right: &LoxValue,
+    op: fn(i64, i64) -> Option<i64>,
+) -> Result<LoxValue, RuntimeError> {
+    let (left, right) = (check_integer_operand(operator, left)?, check_integer_operand(operator, right)?);
+    op(left, right).map(LoxValue::Int).ok_or_else(|| RuntimeError::new(operator, "Shift amount out of range."))
+}
+
+// Integer operands stay exact and must not overflow; anything else is done
+// in floating point.
+fn arithmetic(
+    operator: &Token,
+    left: &LoxValue,
+    right: &LoxValue,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> Result<LoxValue, RuntimeError> {
+    if let (LoxValue::Int(left), LoxValue::Int(right)) = (left, right) {
+        return integer(operator, int_op(*left, *right));
+    }
+    let (left, right) = check_number_operands(operator, left, right)?;
+    Ok(LoxValue::Number(float_op(left, right)))
+}
+
+fn integer(operator: &Token, value: Option<i64>) -> Result<LoxValue, RuntimeError> {
+    value.map(LoxValue::Int).ok_or_else(|| RuntimeError::new(operator, "Integer overflow."))
+}
+
+// Integer `%` and `~/` have no answer for zero, unlike float division.
+fn check_divisor(operator: &Token, divisor: &LoxValue) -> Result<(), RuntimeError> {
+    match divisor {
+        LoxValue::Int(0) => Err(RuntimeError::new(operator, "Division by zero.")),
+        _ => Ok(()),
+    }
+}
+
+fn compare_numbers(
+    operator: &Token,
+    left: &LoxValue,
+    right: &LoxValue,
+    test: fn(cmp::Ordering) -> bool,
+) -> Result<LoxValue, RuntimeError> {
+    let ordering = match (left, right) {
+        (LoxValue::Int(left), LoxValue::Int(right)) => Some(left.cmp(right)),
+        _ => {
+            let (left, right) = check_number_operands(operator, left, right)?;
+            left.partial_cmp(&right)
+        }
+    };
+    Ok(LoxValue::Bool(ordering.is_some_and(test)))
+}
+
 fn is_truthy(object: &LoxValue) -> bool {
     if let LoxValue::Nil = object {
         return false;
@@ -317,7 +860,7 @@ fn is_truthy(object: &LoxValue) -> bool {
     if let LoxValue::Bool(value) = object {
         return *value;
     }
-    return true;
+    true
 }
 
 fn is_equal(a: &LoxValue, b: &LoxValue) -> bool {
@@ -327,5 +870,379 @@ fn is_equal(a: &LoxValue, b: &LoxValue) -> bool {
     if let LoxValue::Nil = a {
         return false;
     }
-    return a == b;
+    // `1 == 1.0`, like every other comparison between the two.
+    if let (LoxValue::Int(_), LoxValue::Number(_)) | (LoxValue::Number(_), LoxValue::Int(_)) = (a, b) {
+        return as_number(a) == as_number(b);
+    }
+    a == b
+}
+
+#[cfg(test)]
+fn run_source(interpreter: &mut Interpreter, source: &str) -> RuntimeResult {
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+    use crate::scope_resolver::ScopeResolver;
+
+    let tokens = Scanner::build(source).scan_tokens();
+    let stmts = Parser::new(tokens).parse().unwrap();
+    ScopeResolver::new(interpreter).resolve(&stmts);
+    interpreter.interpret(&stmts)
+}
+
+#[test]
+fn test_stack_trace() {
+    let source = "fun add(a, b) {\n  return a + b;\n}\nfun wrap(x) {\n  return add(x, \"s\");\n}\nwrap(1);";
+    let mut interpreter = Interpreter::new();
+    let err = run_source(&mut interpreter, source).unwrap_err();
+    let err = err.downcast_ref::<RuntimeError>().unwrap();
+
+    assert_eq!(err.line(), Some(2));
+    assert_eq!(err.trace, vec!["[line 2] in add()", "[line 5] in wrap()", "[line 7] in script"]);
+    assert!(interpreter.call_stack().is_empty());
+}
+
+#[test]
+fn test_string_interpolation() {
+    let mut interpreter = Interpreter::new();
+    let source = "var name = \"Lox\"; var age = 3;\nvar s = \"Hello ${name}, you are ${age + 1}${\"!\"}\";\nvar t = \"${nil}/${true}/${1.5}/${1 == 1}\";\nvar u = \"\\${x}\";";
+    run_source(&mut interpreter, source).unwrap();
+
+    let global = |name: &str| interpreter.environment.borrow().get(name.into()).unwrap();
+    assert_eq!(global("s"), LoxValue::String("Hello Lox, you are 4!".into()));
+    assert_eq!(global("t"), LoxValue::String("nil/true/1.5/true".into()));
+    assert_eq!(global("u"), LoxValue::String("${x}".into()));
+}
+
+#[test]
+fn test_integer_arithmetic() {
+    let mut interpreter = Interpreter::new();
+    let source = "var big = 9007199254740993 + 2; var rem = -7 % 3; var quot = -7 ~/ 2; var fquot = 7.5 ~/ 2;
+var half = 7 / 2; var mixed = 1 + 0.5; var same = 1 == 1.0; var less = 2 < 2.5; var exact = 3 * 4 - 2;";
+    run_source(&mut interpreter, source).unwrap();
+
+    let global = |name: &str| interpreter.environment.borrow().get(name.into()).unwrap();
+    assert_eq!(global("big"), LoxValue::Int(9007199254740995));
+    assert_eq!(global("rem"), LoxValue::Int(-1));
+    assert_eq!(global("quot"), LoxValue::Int(-3));
+    assert_eq!(global("fquot"), LoxValue::Int(3));
+    assert_eq!(global("half"), LoxValue::Number(3.5));
+    assert_eq!(global("mixed"), LoxValue::Number(1.5));
+    assert_eq!(global("same"), LoxValue::Bool(true));
+    assert_eq!(global("less"), LoxValue::Bool(true));
+    assert_eq!(global("exact").to_string(), "10");
+
+    for (source, message) in [
+        ("9223372036854775807 + 1;", "Integer overflow."),
+        ("-(-9223372036854775807 - 1);", "Integer overflow."),
+        ("1 % 0;", "Division by zero."),
+        ("1 ~/ 0;", "Division by zero."),
+        ("1.0 ~/ 0.0;", "Division by zero."),
+        ("1 ~/ -0.0;", "Division by zero."),
+    ] {
+        let err = run_source(&mut interpreter, source).unwrap_err();
+        let err = err.downcast_ref::<RuntimeError>().unwrap();
+        assert_eq!(err.message, message, "{}", source);
+    }
+}
+
+#[test]
+fn test_bitwise_and_power() {
+    let mut interpreter = Interpreter::new();
+    let source = "var bit_and = 0b1100 & 0b1010; var bit_or = 0b1100 | 0b1010; var bit_xor = 0b1100 ^ 0b1010; var bit_not = ~5;
+var shifted = 1 << 62 >> 60; var sign = -16 >> 2; var whole = 6.0 & 3; var mask = 0xFF & 1 == 1;
+var pow = 2 ** 62; var right = 2 ** 3 ** 2; var neg = -2 ** 2; var inverse = 2 ** -1; var root = 9 ** 0.5;";
+    run_source(&mut interpreter, source).unwrap();
+
+    let global = |name: &str| interpreter.environment.borrow().get(name.into()).unwrap();
+    assert_eq!(global("bit_and"), LoxValue::Int(0b1000));
+    assert_eq!(global("bit_or"), LoxValue::Int(0b1110));
+    assert_eq!(global("bit_xor"), LoxValue::Int(0b0110));
+    assert_eq!(global("bit_not"), LoxValue::Int(-6));
+    assert_eq!(global("shifted"), LoxValue::Int(4));
+    assert_eq!(global("sign"), LoxValue::Int(-4));
+    assert_eq!(global("whole"), LoxValue::Int(2));
+    assert_eq!(global("mask"), LoxValue::Bool(true));
+    assert_eq!(global("pow"), LoxValue::Int(1 << 62));
+    assert_eq!(global("right"), LoxValue::Int(512));
+    assert_eq!(global("neg"), LoxValue::Int(-4));
+    assert_eq!(global("inverse"), LoxValue::Number(0.5));
+    assert_eq!(global("root"), LoxValue::Number(3.0));
+
+    for (source, message) in [
+        ("1.5 | 1;", "Operand must be an integer. Got 1.5"),
+        ("~\"a\";", "Operand must be an integer. Got a"),
+        ("1 << 64;", "Shift amount out of range."),
+        ("1 >> -1;", "Shift amount out of range."),
+        ("2 ** 63;", "Integer overflow."),
+    ] {
+        let err = run_source(&mut interpreter, source).unwrap_err();
+        let err = err.downcast_ref::<RuntimeError>().unwrap();
+        assert_eq!(err.message, message, "{}", source);
+    }
+}
+
+#[test]
+fn test_compound_assignment() {
+    let mut interpreter = Interpreter::new();
+    let source = "var total = 10; total += 5; total -= 3; total *= 2; total %= 7; var half = 3; half /= 2;
+var s = \"a\"; s += \"b\"; var i = 0; var before = i++; var after = ++i; var down = i--;
+fun count() { var n = 0; for (var k = 0; k < 5; k++) n += k; return n; }
+var counted = count(); var pick = counted > 5 ? \"big\" : counted > 0 ? \"small\" : \"none\";
+var lazy = true ? 1 : undefined;";
+    run_source(&mut interpreter, source).unwrap();
+
+    let global = |name: &str| interpreter.environment.borrow().get(name.into()).unwrap();
+    assert_eq!(global("total"), LoxValue::Int(3));
+    assert_eq!(global("half"), LoxValue::Number(1.5));
+    assert_eq!(global("s"), LoxValue::String("ab".into()));
+    assert_eq!(global("before"), LoxValue::Int(0));
+    assert_eq!(global("after"), LoxValue::Int(2));
+    assert_eq!(global("down"), LoxValue::Int(2));
+    assert_eq!(global("i"), LoxValue::Int(1));
+    assert_eq!(global("counted"), LoxValue::Int(10));
+    assert_eq!(global("pick"), LoxValue::String("big".into()));
+    assert_eq!(global("lazy"), LoxValue::Int(1));
+
+    for (source, message) in [("s++;", "Operands must be two numbers or two strings."), ("missing += 1;", "Undefined variable 'missing'.")] {
+        let err = run_source(&mut interpreter, source).unwrap_err();
+        let err = err.downcast_ref::<RuntimeError>().unwrap();
+        assert_eq!(err.message, message, "{}", source);
+    }
+}
+
+#[test]
+fn test_constants() {
+    use crate::Lox;
+
+    for (source, message) in [
+        ("const limit = 1;\nlimit = 2;", "ResolveError at line 2 column 22: Can't assign to constant 'limit' declared at line 1 column 11."),
+        ("fun f() {\n  const step = 1;\n  step += 1;\n}", "ResolveError at line 3 column 34: Can't assign to constant 'step' declared at line 2 column 22."),
+        ("const n = 0;\nfun f() { n++; }", "ResolveError at line 2 column 24: Can't assign to constant 'n' declared at line 1 column 7."),
+    ] {
+        let mut lox = Lox::new();
+        lox.run(source);
+        assert!(lox.had_error && !lox.had_runtime_error, "{}", source);
+        assert_eq!(lox.last_error.as_deref(), Some(message));
+    }
+
+    // Globals declared after the code that assigns them, or in an earlier
+    // run, are only caught when the assignment runs.
+    let mut interpreter = Interpreter::new();
+    run_source(&mut interpreter, "fun reset() { limit = 0; }\nconst limit = 10;\nvar shadowed = 1;").unwrap();
+    for source in ["reset();", "limit = 0;", "{ var limit = 1; limit = 2; } limit -= 1;"] {
+        let err = run_source(&mut interpreter, source).unwrap_err();
+        let err = err.downcast_ref::<RuntimeError>().unwrap();
+        assert_eq!(err.message, "Can't assign to constant 'limit'.", "{}", source);
+    }
+    run_source(&mut interpreter, "var limit = 3; limit = 4;").unwrap();
+    assert_eq!(interpreter.environment.borrow().get("limit".into()).unwrap(), LoxValue::Int(4));
+}
+
+#[test]
+fn test_stack_trace_top_level() {
+    let mut interpreter = Interpreter::new();
+    let err = run_source(&mut interpreter, "var a = 1;\nprint -\"a\";").unwrap_err();
+    let err = err.downcast_ref::<RuntimeError>().unwrap();
+
+    assert_eq!(err.trace, vec!["[line 2] in script"]);
+}
+
+#[test]
+fn test_call_stack_from_native() {
+    use crate::function::LoxCallable;
+
+    struct Depth;
+    impl std::fmt::Display for Depth {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "<native fn depth>")
+        }
+    }
+    impl LoxCallable for Depth {
+        fn name(&self) -> &str {
+            "depth"
+        }
+        fn arity(&self) -> usize {
+            0
+        }
+        fn call(&self, interpreter: &Interpreter, _arguments: Vec<LoxValue>) -> EvalResult {
+            let frames = interpreter.call_stack();
+            let names: Vec<&str> = frames.iter().map(|frame| frame.function.as_str()).collect();
+            assert_eq!(names, vec!["outer", "inner", "depth"]);
+            Ok(LoxValue::Int(frames.len() as i64))
+        }
+    }
+
+    let mut interpreter = Interpreter::new();
+    interpreter.environment.borrow_mut().define("depth".into(), LoxValue::Callable(Rc::new(Depth)));
+    let source = "fun inner() { return depth(); }\nfun outer() { return inner(); }\nvar d = outer();";
+    run_source(&mut interpreter, source).unwrap();
+
+    let d = interpreter.environment.borrow().get("d".into()).unwrap();
+    assert_eq!(d, LoxValue::Int(3));
+}
+
+#[test]
+fn test_stack_overflow() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_max_call_depth(100);
+    let err = run_source(&mut interpreter, "fun f() {\n  f();\n}\nf();").unwrap_err();
+    let err = err.downcast_ref::<RuntimeError>().unwrap();
+
+    assert_eq!(err.message, "Stack overflow.");
+    assert_eq!(err.trace, vec!["[line 2] in f()", "[previous line repeated 99 more times]", "[line 4] in script"]);
+    assert!(interpreter.call_stack().is_empty());
+}
+
+#[test]
+fn test_deep_recursion() {
+    // Far deeper than the test thread's native stack allows without growing it.
+    let mut interpreter = Interpreter::new();
+    let source = "fun count(n) {\n  if (n == 0) return 0;\n  return 1 + count(n - 1);\n}\nvar c = count(5000);";
+    run_source(&mut interpreter, source).unwrap();
+
+    let c = interpreter.environment.borrow().get("c".into()).unwrap();
+    assert_eq!(c, LoxValue::Int(5000));
+}
+
+#[test]
+fn test_fuel_budget() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_fuel(Some(1000));
+    let err = run_source(&mut interpreter, "while (true) {}").unwrap_err();
+
+    assert!(err.downcast_ref::<RuntimeError>().is_none());
+    assert_eq!(err.downcast_ref::<RuntimeInterrupt>().unwrap().kind, InterruptKind::OutOfFuel);
+    assert_eq!(interpreter.remaining_fuel(), Some(0));
+
+    // The interpreter stays usable once the budget is refilled.
+    interpreter.set_fuel(Some(1000));
+    run_source(&mut interpreter, "fun f() { return 1; }\nvar x = f();").unwrap();
+    assert_eq!(interpreter.remaining_fuel(), Some(999));
+}
+
+#[test]
+fn test_deadline() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_deadline(Some(Instant::now() + std::time::Duration::from_millis(20)));
+    let err = run_source(&mut interpreter, "fun spin() { while (true) {} }\nspin();").unwrap_err();
+
+    assert_eq!(err.downcast_ref::<RuntimeInterrupt>().unwrap().kind, InterruptKind::DeadlineExceeded);
+    assert!(interpreter.call_stack().is_empty());
+}
+
+#[test]
+fn test_cancellation() {
+    let flag = Arc::new(AtomicBool::new(false));
+    let canceller = {
+        let flag = flag.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            flag.store(true, Ordering::Relaxed);
+        })
+    };
+
+    let mut interpreter = Interpreter::new();
+    interpreter.set_cancel_flag(Some(flag));
+    let err = run_source(&mut interpreter, "var i = 0;\nwhile (true) { i = i + 1; }").unwrap_err();
+    canceller.join().unwrap();
+
+    assert_eq!(err.downcast_ref::<RuntimeInterrupt>().unwrap().kind, InterruptKind::Cancelled);
+}
+
+#[test]
+fn test_memory_limit() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_memory_limit(Some(64 * 1024));
+    let source = "var s = \"x\";\nwhile (true) {\n  s = s + s;\n}";
+    let err = run_source(&mut interpreter, source).unwrap_err();
+    let err = err.downcast_ref::<RuntimeError>().unwrap();
+
+    assert_eq!(err.message, "Out of memory.");
+    assert_eq!(err.line(), Some(3));
+    let stats = interpreter.memory_stats();
+    assert!(stats.peak <= 64 * 1024);
+    assert!(stats.peak > 32 * 1024);
+}
+
+#[test]
+fn test_memory_stats() {
+    let mut interpreter = Interpreter::new();
+    run_source(&mut interpreter, "fun greet(name) { return \"hi \" + name; }\nvar g = greet(\"bob\");").unwrap();
+
+    let stats = interpreter.memory_stats();
+    assert!(stats.allocated >= memory::string_size("hi bob"));
+    assert_eq!(stats.peak, stats.live);
+}
+
+#[test]
+fn test_gc_reclaims_scopes() {
+    let mut interpreter = Interpreter::new();
+    let source = "fun square(n) {\n  var result = n * n;\n  return result;\n}\nfor (var i = 0; i < 1000; i = i + 1) {\n  square(i);\n}";
+    run_source(&mut interpreter, source).unwrap();
+    interpreter.collect_garbage();
+
+    let stats = interpreter.gc_stats();
+    assert!(stats.scopes_freed >= 1000);
+    // Only the globals and the natives are left.
+    assert_eq!(stats.live_scopes, 2);
+    assert_eq!(interpreter.environment.borrow().live_scopes(), 2);
+    assert!(stats.bytes_reclaimed > 0);
+    assert!(interpreter.memory_stats().live < interpreter.memory_stats().peak);
+}
+
+#[test]
+fn test_gc_stress_keeps_closures() {
+    let mut interpreter = Interpreter::new();
+    interpreter.set_gc_stress(true);
+    let source = "
+fun makeCounter() {
+  var i = 0;
+  fun count() {
+    i = i + 1;
+    return i;
+  }
+  return count;
+}
+fun makeAdder(n) {
+  fun add(x) { return x + n; }
+  return add;
+}
+fun apply(f, x) { return f(x); }
+
+var a = makeCounter();
+var b = makeCounter();
+a();
+a();
+b();
+var counted = a() + b() * 10;
+var applied = apply(makeAdder(1), makeAdder(2)(3));
+var chained = makeAdder(\"a\")(\"b\");
+";
+    run_source(&mut interpreter, source).unwrap();
+
+    let env = interpreter.environment.borrow();
+    assert_eq!(env.get("counted".into()).unwrap(), LoxValue::Int(23));
+    assert_eq!(env.get("applied".into()).unwrap(), LoxValue::Int(6));
+    assert_eq!(env.get("chained".into()).unwrap(), LoxValue::String("ba".into()));
+    assert!(interpreter.gc_stats().collections > 20);
+}
+
+#[test]
+fn test_profile() {
+    let mut interpreter = Interpreter::new();
+    interpreter.start_profiling();
+    let source = "fun fib(n) {\n  if (n < 2) return n;\n  return fib(n - 2) + fib(n - 1);\n}\nfun run() { return fib(10); }\nrun();";
+    run_source(&mut interpreter, source).unwrap();
+    let profile = interpreter.finish_profiling().unwrap();
+
+    assert_eq!(profile.functions["run"].calls, 1);
+    assert_eq!(profile.functions["fib"].calls, 177);
+    assert!(profile.functions["run"].total >= profile.functions["fib"].total);
+    assert_eq!(profile.lines[&(0, 2)].hits, 177);
+    assert_eq!(profile.lines[&(0, 3)].hits, 88);
+    assert_eq!(profile.lines[&(0, 6)].hits, 1);
+
+    let collapsed = profile.collapsed();
+    assert!(collapsed.lines().any(|line| line.starts_with("script;run;fib;fib ")));
+    assert!(interpreter.finish_profiling().is_none());
 }
@@ -1,24 +1,95 @@
-mod errors;
-mod scanner;
+pub mod errors;
+pub mod scanner;
 mod parser;
-mod interpreter;
+pub mod interpreter;
 mod astprinter;
-mod ast;
-mod environment;
-mod token;
-mod function;
+pub mod ast;
+pub mod environment;
+pub mod token;
+pub mod function;
 mod scope_resolver;
+pub mod memory;
+pub mod interner;
+pub mod profiler;
+pub mod coverage;
+pub mod debugger;
+pub mod dap;
+pub mod framing;
+pub mod formatter;
+pub mod lsp;
+pub mod module;
+#[cfg(feature = "nan-boxing")]
+pub mod nanbox;
 
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use ast::RcStmt;
+use astprinter::{AstJson, AstPrinter};
+use errors::{RuntimeError, RuntimeInterrupt};
 use interpreter::Interpreter;
 use parser::Parser;
-use astprinter::AstPrinter;
 use scanner::Scanner;
 use scope_resolver::ScopeResolver;
+use token::{TokenLiteral, TokenType};
+
+// Output forms of `Lox::dump_ast`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AstFormat {
+    // One Lisp-style line per top-level statement.
+    SExpr,
+    // A JSON array of nodes with their kinds, spans and resolved depths.
+    Json,
+}
 
 pub struct Lox {
     interpreter: Interpreter,
     pub had_runtime_error: bool,
     pub had_error: bool,
+    // Phase timings of the most recent `run`.
+    pub timings: PhaseTimings,
+    // Message of the error that stopped the most recent `run`, if any.
+    pub last_error: Option<String>,
+}
+
+// Wall-clock time spent in each phase of a run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PhaseTimings {
+    pub scan: Duration,
+    pub parse: Duration,
+    pub resolve: Duration,
+    pub execute: Duration,
+}
+
+impl PhaseTimings {
+    pub fn total(&self) -> Duration {
+        self.scan + self.parse + self.resolve + self.execute
+    }
+}
+
+impl Display for PhaseTimings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let phases = [
+            ("scan", self.scan),
+            ("parse", self.parse),
+            ("resolve", self.resolve),
+            ("execute", self.execute),
+            ("total", self.total()),
+        ];
+        for (i, (phase, time)) in phases.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:<8} {:>10.3}ms", phase, time.as_secs_f64() * 1000.0)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Lox {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Lox {
@@ -27,13 +98,21 @@ impl Lox {
             interpreter: Interpreter::new(),
             had_runtime_error: false,
             had_error: false,
+            timings: PhaseTimings::default(),
+            last_error: None,
         }
     }
+    // The interpreter that runs every script, for configuring limits and
+    // reading statistics from the embedding host.
+    pub fn interpreter(&self) -> &Interpreter {
+        &self.interpreter
+    }
     pub fn run_file(&mut self, path: &str) {
         // 读文件
         let source = std::fs::read_to_string(path).unwrap();
+        self.interpreter.set_script_path(std::path::Path::new(path));
         // 调用run
-        self.run(&source);
+        self.run_named(path, &source);
     }
     pub fn run_prompt(&mut self) {
         // InputStreamReader input = new InputStreamReader(System.in);
@@ -46,43 +125,140 @@ impl Lox {
         //     run(line);
         // }
     }
-    fn run(&mut self, source: &String) {
+    pub fn run(&mut self, source: &str) {
+        self.run_named("script", source);
+    }
+    // `name` identifies the script in coverage reports.
+    pub fn run_named(&mut self, name: &str, source: &str) {
         // println!("Running: {}", source);
-        
+        self.timings = PhaseTimings::default();
+        self.last_error = None;
+        let Some(stmts) = self.front_end(source) else {
+            return;
+        };
+        self.interpreter.cover(0, name, source, &stmts);
+
+        let start = Instant::now();
+        let result = self.interpreter.interpret(&stmts);
+        self.timings.execute = start.elapsed();
+        if let Err(e) = result {
+            if let Some(e) = e.downcast_ref::<RuntimeError>() {
+                self.had_runtime_error = true;
+                self.report(e.to_string());
+            } else if let Some(e) = e.downcast_ref::<RuntimeInterrupt>() {
+                self.had_runtime_error = true;
+                self.report(e.to_string());
+            }
+        }
+    }
+    // Scans, parses and resolves `source` and returns its syntax tree
+    // instead of running it.
+    pub fn dump_ast(&mut self, source: &str, format: AstFormat) -> Option<String> {
+        self.timings = PhaseTimings::default();
+        self.last_error = None;
+        let stmts = self.front_end(source)?;
+        let dump = match format {
+            AstFormat::SExpr => {
+                let printer = AstPrinter::new();
+                let lines: Vec<String> = stmts.iter().map(|stmt| printer.print_stmt(stmt.clone()) + "\n").collect();
+                lines.concat()
+            }
+            AstFormat::Json => {
+                let locals = self.interpreter.locals.borrow();
+                let printer = AstJson::new(&locals);
+                let program: Vec<serde_json::Value> = stmts.iter().map(|stmt| printer.print_stmt(stmt.clone())).collect();
+                serde_json::to_string_pretty(&program).unwrap() + "\n"
+            }
+        };
+        Some(dump)
+    }
+    // One line per token of `source`: span, type, lexeme and literal.
+    // Lexical errors are listed where they occur and also reported.
+    pub fn dump_tokens(&mut self, source: &str, trivia: bool) -> String {
+        self.last_error = None;
         let scanner = Scanner::build(source);
-        let tokens = scanner.scan_tokens();
+        let scanner = if trivia { scanner.with_trivia() } else { scanner };
+        let mut out = String::new();
+        for item in scanner {
+            match item {
+                Ok(token) => {
+                    let span = format!("{}:{}..{}", token.start_line(), token.start(), token.offset);
+                    let literal = match &token.literal {
+                        TokenLiteral::Int(n) => format!(" {}", n),
+                        TokenLiteral::Number(n) if token._type == TokenType::NUMBER => format!(" {:?}", n),
+                        TokenLiteral::String(s) if matches!(token._type, TokenType::STRING | TokenType::INTERPOLATION) => {
+                            format!(" {:?}", s)
+                        }
+                        _ => String::new(),
+                    };
+                    let kind = format!("{:?}", token._type);
+                    out.push_str(&format!("{:<12} {:<12} {:?}{}\n", span, kind, token.lexeme, literal));
+                }
+                Err(error) => {
+                    let span = format!("{}:{}", error.line, error.offset);
+                    out.push_str(&format!("{:<12} {:<12} {}\n", span, "ERROR", error.message));
+                    self.had_error = true;
+                    self.report(error.to_string());
+                }
+            }
+        }
+        out
+    }
+    // Every phase before execution. Errors are reported, and stop the run.
+    fn front_end(&mut self, source: &str) -> Option<Vec<RcStmt>> {
+        let start = Instant::now();
+        let scanner = Scanner::build(source);
+        let (tokens, errors) = scanner.scan();
+        self.timings.scan = start.elapsed();
         // scanner dropped here
+        if !errors.is_empty() {
+            self.had_error = true;
+            for error in errors {
+                self.report(error.to_string());
+            }
+            return None;
+        }
 
         // For now, just print the tokens.
         // for token in tokens.iter() {
         //     println!("{:?}", token);
         // }
 
+        let start = Instant::now();
         let parser: Parser = Parser::new(tokens);
         let stmts = match parser.parse() {
             Ok(stmts) => stmts,
             Err(e) => {
                 self.had_error = true;
                 // Stop if there was a syntax error.
-                panic!("{}", e.0);
+                self.report(e.0);
+                return None;
             },
         };
         // parser dropped here
+        self.timings.parse = start.elapsed();
 
-        // let ast_printer = AstPrinter::new();
-        // for stmt in stmts.iter() {
-        //     println!("{}", ast_printer.print_stmt(stmt.clone()));
-        // }
-        let resolver = ScopeResolver::new(&mut self.interpreter);
+        let start = Instant::now();
+        let resolver = ScopeResolver::new(&self.interpreter);
         resolver.resolve(&stmts);
-        
-        match self.interpreter.interpret(&stmts) {
-            Ok(_) => {},
-            Err(e) => {
-                self.had_error = true;
-                panic!("{:?}", e);
-            },
-        };
+        let resolution = resolver.finish();
+        self.timings.resolve = start.elapsed();
+        // Warnings are shown but don't stop the script.
+        for warning in resolution.warnings {
+            eprintln!("{}", warning);
+        }
+        if !resolution.errors.is_empty() {
+            self.had_error = true;
+            for error in resolution.errors {
+                self.report(error.to_string());
+            }
+            return None;
+        }
+        Some(stmts)
+    }
+    fn report(&mut self, message: String) {
+        eprintln!("{}", message);
+        self.last_error = Some(message);
     }
 }
 
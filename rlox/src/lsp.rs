@@ -0,0 +1,500 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::{BufRead, Write};
+
+use serde_json::{json, Value};
+
+use crate::errors::Severity;
+use crate::framing;
+use crate::interpreter::Interpreter;
+use crate::parser::Parser;
+use crate::scanner::Scanner;
+use crate::scope_resolver::{DeclarationKind, ScopeResolver, SymbolTable};
+use crate::token::{Token, TokenType, KEYWORDS};
+
+// Language Server Protocol over a pair of streams, for `lox-lsp`. Documents
+// are re-analyzed in full on every change, so the server only advertises
+// full text sync.
+
+const TEXT_DOCUMENT_SYNC_FULL: i64 = 1;
+const SEVERITY_ERROR: i64 = 1;
+const SEVERITY_WARNING: i64 = 2;
+const SYMBOL_MODULE: i64 = 2;
+const SYMBOL_FUNCTION: i64 = 12;
+const SYMBOL_VARIABLE: i64 = 13;
+const SYMBOL_CONSTANT: i64 = 14;
+const COMPLETION_FUNCTION: i64 = 3;
+const COMPLETION_VARIABLE: i64 = 6;
+const COMPLETION_MODULE: i64 = 9;
+const COMPLETION_KEYWORD: i64 = 14;
+const COMPLETION_CONSTANT: i64 = 21;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+// What is known about one open document.
+struct Document {
+    text: String,
+    tokens: Vec<Token>,
+    // LSP position (line, UTF-16 column) of every char index, plus one past
+    // the end.
+    positions: Vec<(usize, usize)>,
+    diagnostics: Vec<Value>,
+    symbols: SymbolTable,
+    // Char range each declaration is visible in, parallel to
+    // `symbols.declarations`.
+    visible: Vec<(usize, usize)>,
+}
+
+impl Document {
+    fn analyze(text: String) -> Self {
+        let mut positions = Vec::with_capacity(text.len() + 1);
+        let (mut line, mut column) = (0, 0);
+        for c in text.chars() {
+            positions.push((line, column));
+            if c == '\n' {
+                line += 1;
+                column = 0;
+            } else {
+                column += c.len_utf16();
+            }
+        }
+        positions.push((line, column));
+
+        let mut document = Document {
+            text,
+            tokens: vec![],
+            positions,
+            diagnostics: vec![],
+            symbols: SymbolTable::default(),
+            visible: vec![],
+        };
+        let (tokens, scan_errors) = Scanner::build(&document.text).scan();
+        for error in scan_errors {
+            let range = document.range(error.offset.saturating_sub(1), error.offset);
+            document.diagnostic(range, SEVERITY_ERROR, &error.message);
+        }
+        document.tokens = tokens;
+
+        match Parser::new(document.tokens.clone()).parse() {
+            Ok(stmts) => {
+                // The resolver records local depths in an interpreter; this
+                // one is thrown away.
+                let interpreter = Interpreter::new();
+                let resolver = ScopeResolver::new(&interpreter);
+                resolver.resolve(&stmts);
+                let resolution = resolver.finish();
+                for error in resolution.errors.iter().chain(resolution.warnings.iter()) {
+                    let (start, end) = error.span();
+                    let severity = match error.severity {
+                        Severity::Error => SEVERITY_ERROR,
+                        Severity::Warning => SEVERITY_WARNING,
+                    };
+                    document.diagnostic(document.range(start, end), severity, &error.message);
+                }
+                document.symbols = resolution.symbols;
+            }
+            Err(error) => {
+                let range = match &error.1 {
+                    Some(token) => document.token_range(token),
+                    None => document.range(0, 0),
+                };
+                document.diagnostic(range, SEVERITY_ERROR, &error.0);
+            }
+        }
+        document.visible = document.visible_ranges();
+        document
+    }
+
+    fn diagnostic(&mut self, range: Value, severity: i64, message: &str) {
+        self.diagnostics.push(json!({ "range": range, "severity": severity, "source": "lox", "message": message }));
+    }
+
+    fn position(&self, index: usize) -> Value {
+        let (line, character) = self.positions[index.min(self.positions.len() - 1)];
+        json!({ "line": line, "character": character })
+    }
+
+    fn range(&self, start: usize, end: usize) -> Value {
+        json!({ "start": self.position(start), "end": self.position(end) })
+    }
+
+    fn token_range(&self, token: &Token) -> Value {
+        self.range(token.start(), token.offset)
+    }
+
+    // Char index of an LSP position.
+    fn index(&self, position: &Value) -> usize {
+        let line = position["line"].as_u64().unwrap_or(0) as usize;
+        let character = position["character"].as_u64().unwrap_or(0) as usize;
+        self.positions
+            .iter()
+            .position(|&(l, c)| (l == line && c >= character) || l > line)
+            .unwrap_or(self.positions.len() - 1)
+    }
+
+    // A declaration is visible from where it is made to the end of its
+    // block; a parameter from its name to the end of the function body.
+    // Globals are visible everywhere, since they are bound late.
+    fn visible_ranges(&self) -> Vec<(usize, usize)> {
+        let end = self.positions.len() - 1;
+        // Closing char index of every `{`, by token index.
+        let mut closes = HashMap::new();
+        let mut open = vec![];
+        for (i, token) in self.tokens.iter().enumerate() {
+            match token._type {
+                TokenType::LEFTBRACE => open.push(i),
+                TokenType::RIGHTBRACE => {
+                    if let Some(start) = open.pop() {
+                        closes.insert(start, token.offset);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let close_of = |brace: usize| closes.get(&brace).copied().unwrap_or(end);
+
+        self.symbols
+            .declarations
+            .iter()
+            .map(|declaration| {
+                let start = declaration.name.start();
+                if declaration.depth == 0 {
+                    return (0, end);
+                }
+                let Some(at) = self.tokens.iter().position(|token| token.offset == declaration.name.offset) else {
+                    return (start, end);
+                };
+                let brace = if declaration.kind == DeclarationKind::Parameter {
+                    self.tokens[at..].iter().position(|token| token._type == TokenType::LEFTBRACE).map(|i| at + i)
+                } else {
+                    // The innermost `{` still open at the declaration.
+                    (0..at).rev().find(|&i| self.tokens[i]._type == TokenType::LEFTBRACE && close_of(i) > start)
+                };
+                (start, brace.map_or(end, close_of))
+            })
+            .collect()
+    }
+
+    // The declaration named, or referred to, by the identifier at `index`.
+    fn declaration_at(&self, index: usize) -> Option<usize> {
+        let token = self.tokens.iter().find(|token| {
+            token._type == TokenType::IDENTIFIER && token.start() <= index && index <= token.offset
+        })?;
+        let declared = self.symbols.declarations.iter().position(|declaration| declaration.name.offset == token.offset);
+        declared.or_else(|| {
+            self.symbols
+                .references
+                .iter()
+                .find(|reference| reference.name.offset == token.offset)
+                .and_then(|reference| reference.declaration)
+        })
+    }
+}
+
+pub struct Server {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+}
+
+impl Server {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Self { input, output, documents: HashMap::new(), shutdown: false }
+    }
+
+    // Serves requests until the client sends `exit` or closes the stream.
+    // Returns whether the client asked for a shutdown first.
+    pub fn run(&mut self) -> bool {
+        while let Some(message) = framing::read_message(&mut *self.input) {
+            let method = message["method"].as_str().unwrap_or("").to_string();
+            let params = &message["params"];
+            let result = match method.as_str() {
+                "initialize" => Some(self.initialize()),
+                "shutdown" => {
+                    self.shutdown = true;
+                    Some(Value::Null)
+                }
+                "exit" => return self.shutdown,
+                "textDocument/didOpen" => {
+                    let document = &params["textDocument"];
+                    self.update(document["uri"].as_str().unwrap_or(""), document["text"].as_str().unwrap_or(""));
+                    None
+                }
+                "textDocument/didChange" => {
+                    // Full sync: the last change holds the whole text.
+                    let text = params["contentChanges"].as_array().and_then(|changes| changes.last());
+                    let text = text.and_then(|change| change["text"].as_str()).unwrap_or("");
+                    self.update(params["textDocument"]["uri"].as_str().unwrap_or(""), text);
+                    None
+                }
+                "textDocument/didClose" => {
+                    let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+                    self.documents.remove(uri);
+                    self.notify("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": [] }));
+                    None
+                }
+                "textDocument/definition" => Some(self.definition(params)),
+                "textDocument/references" => Some(self.references(params)),
+                "textDocument/hover" => Some(self.hover(params)),
+                "textDocument/documentSymbol" => Some(self.document_symbols(params)),
+                "textDocument/completion" => Some(self.completion(params)),
+                _ => None,
+            };
+            // Notifications have no id and get no reply.
+            if message.get("id").is_none() {
+                continue;
+            }
+            match result {
+                Some(result) => self.send(json!({ "jsonrpc": "2.0", "id": message["id"], "result": result })),
+                None => self.send(json!({
+                    "jsonrpc": "2.0",
+                    "id": message["id"],
+                    "error": { "code": METHOD_NOT_FOUND, "message": format!("Unknown method {}", method) },
+                })),
+            }
+        }
+        self.shutdown
+    }
+
+    fn send(&mut self, message: Value) {
+        framing::write_message(&mut *self.output, &message);
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+    }
+
+    fn initialize(&self) -> Value {
+        json!({
+            "capabilities": {
+                "textDocumentSync": TEXT_DOCUMENT_SYNC_FULL,
+                "definitionProvider": true,
+                "referencesProvider": true,
+                "hoverProvider": true,
+                "documentSymbolProvider": true,
+                "completionProvider": {},
+            },
+            "serverInfo": { "name": "lox-lsp" },
+        })
+    }
+
+    fn update(&mut self, uri: &str, text: &str) {
+        let document = Document::analyze(text.to_string());
+        let diagnostics = document.diagnostics.clone();
+        self.documents.insert(uri.to_string(), document);
+        self.notify("textDocument/publishDiagnostics", json!({ "uri": uri, "diagnostics": diagnostics }));
+    }
+
+    // The document and char index a position request points at.
+    fn lookup<'a>(&'a self, params: &'a Value) -> Option<(&'a str, &'a Document, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let document = self.documents.get(uri)?;
+        Some((uri, document, document.index(&params["position"])))
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let Some((uri, document, index)) = self.lookup(params) else {
+            return Value::Null;
+        };
+        match document.declaration_at(index) {
+            Some(declaration) => {
+                let name = &document.symbols.declarations[declaration].name;
+                json!({ "uri": uri, "range": document.token_range(name) })
+            }
+            None => Value::Null,
+        }
+    }
+
+    fn references(&self, params: &Value) -> Value {
+        let Some((uri, document, index)) = self.lookup(params) else {
+            return Value::Null;
+        };
+        let Some(declaration) = document.declaration_at(index) else {
+            return json!([]);
+        };
+        let mut locations = vec![];
+        if params["context"]["includeDeclaration"].as_bool().unwrap_or(true) {
+            let name = &document.symbols.declarations[declaration].name;
+            locations.push(json!({ "uri": uri, "range": document.token_range(name) }));
+        }
+        // In source order; the resolver visits an assignment's value first.
+        let mut references: Vec<&Token> = document
+            .symbols
+            .references
+            .iter()
+            .filter(|reference| reference.declaration == Some(declaration))
+            .map(|reference| &reference.name)
+            .collect();
+        references.sort_by_key(|name| name.offset);
+        for name in references {
+            locations.push(json!({ "uri": uri, "range": document.token_range(name) }));
+        }
+        json!(locations)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let Some((_, document, index)) = self.lookup(params) else {
+            return Value::Null;
+        };
+        let Some(declaration) = document.declaration_at(index) else {
+            return Value::Null;
+        };
+        let declaration = &document.symbols.declarations[declaration];
+        let line = document.positions[declaration.name.start().min(document.positions.len() - 1)].0 + 1;
+        let doc = declaration.doc.as_ref().map_or(String::new(), |doc| format!("{}\n\n", doc));
+        json!({
+            "contents": {
+                "kind": "markdown",
+                "value": format!("```lox\n{}\n```\n{}Declared on line {}", declaration.detail, doc, line),
+            },
+        })
+    }
+
+    fn document_symbols(&self, params: &Value) -> Value {
+        let Some(uri) = params["textDocument"]["uri"].as_str() else {
+            return Value::Null;
+        };
+        let Some(document) = self.documents.get(uri) else {
+            return Value::Null;
+        };
+        let symbols: Vec<Value> = document
+            .symbols
+            .declarations
+            .iter()
+            .filter_map(|declaration| {
+                let kind = match declaration.kind {
+                    DeclarationKind::Function => SYMBOL_FUNCTION,
+                    DeclarationKind::Variable => SYMBOL_VARIABLE,
+                    DeclarationKind::Constant => SYMBOL_CONSTANT,
+                    DeclarationKind::Module => SYMBOL_MODULE,
+                    DeclarationKind::Parameter => return None,
+                };
+                Some(json!({
+                    "name": declaration.name.lexeme.as_ref(),
+                    "kind": kind,
+                    "location": { "uri": uri, "range": document.token_range(&declaration.name) },
+                }))
+            })
+            .collect();
+        json!(symbols)
+    }
+
+    fn completion(&self, params: &Value) -> Value {
+        let Some((_, document, index)) = self.lookup(params) else {
+            return json!([]);
+        };
+        let mut items = vec![];
+        let mut seen = BTreeSet::new();
+        for (declaration, &(start, end)) in document.symbols.declarations.iter().zip(document.visible.iter()) {
+            let in_scope = (start..=end).contains(&index);
+            if !in_scope || !seen.insert(declaration.name.lexeme.clone()) {
+                continue;
+            }
+            let kind = match declaration.kind {
+                DeclarationKind::Function => COMPLETION_FUNCTION,
+                DeclarationKind::Module => COMPLETION_MODULE,
+                DeclarationKind::Constant => COMPLETION_CONSTANT,
+                _ => COMPLETION_VARIABLE,
+            };
+            items.push(json!({ "label": declaration.name.lexeme.as_ref(), "kind": kind, "detail": declaration.detail }));
+        }
+        let keywords: BTreeSet<String> = KEYWORDS.lock().unwrap().keys().cloned().collect();
+        for keyword in keywords {
+            items.push(json!({ "label": keyword, "kind": COMPLETION_KEYWORD }));
+        }
+        json!(items)
+    }
+}
+
+#[test]
+fn test_lsp_session() {
+    use crate::debugger::SharedOutput;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let uri = "file:///test.lox";
+    let text = "var total = 0;\nfun add(a, b) {\n  var sum = a + b;\n  return sum;\n}\ntotal = add(total, 2);\n";
+    let position = |line: usize, character: usize| json!({ "textDocument": { "uri": uri }, "position": { "line": line, "character": character } });
+    let requests = [
+        json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+        json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": { "textDocument": { "uri": uri, "text": text } } }),
+        // `add` in the call on the last line.
+        json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/definition", "params": position(5, 9) }),
+        json!({ "jsonrpc": "2.0", "id": 3, "method": "textDocument/references", "params": position(0, 5) }),
+        json!({ "jsonrpc": "2.0", "id": 4, "method": "textDocument/hover", "params": position(3, 10) }),
+        json!({ "jsonrpc": "2.0", "id": 5, "method": "textDocument/documentSymbol", "params": { "textDocument": { "uri": uri } } }),
+        json!({ "jsonrpc": "2.0", "id": 6, "method": "textDocument/completion", "params": position(3, 2) }),
+        json!({ "jsonrpc": "2.0", "id": 7, "method": "textDocument/completion", "params": position(5, 0) }),
+        json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": { "textDocument": { "uri": uri }, "contentChanges": [{ "text": "{ var x = x; }" }] } }),
+        json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": { "textDocument": { "uri": uri }, "contentChanges": [{ "text": "print 1;\nprint 1 +;" }] } }),
+        json!({ "jsonrpc": "2.0", "id": 8, "method": "textDocument/formatting", "params": {} }),
+        json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": { "textDocument": { "uri": uri }, "contentChanges": [{ "text": "/// Adds `a` and `b`.\n/// Pure.\nfun add(a, b) {}\nadd(1, 2);" }] } }),
+        json!({ "jsonrpc": "2.0", "id": 10, "method": "textDocument/hover", "params": position(3, 1) }),
+        json!({ "jsonrpc": "2.0", "id": 9, "method": "shutdown" }),
+        json!({ "jsonrpc": "2.0", "method": "exit" }),
+    ];
+    let input: String = requests.into_iter().map(framing::frame).collect();
+    let output = Rc::new(RefCell::new(vec![]));
+    let mut server = Server::new(Box::new(std::io::Cursor::new(input)), Box::new(SharedOutput(output.clone())));
+    assert!(server.run());
+
+    let output = output.borrow().clone();
+    let mut reader = std::io::Cursor::new(output);
+    let messages: Vec<Value> = std::iter::from_fn(|| framing::read_message(&mut reader)).collect();
+    let response = |id: i64| messages.iter().find(|m| m["id"] == id).unwrap().clone();
+    let labels = |result: &Value| -> Vec<String> {
+        result.as_array().unwrap().iter().map(|item| item["label"].as_str().unwrap().to_string()).collect()
+    };
+    let range = |line: usize, start: usize, end: usize| {
+        json!({ "start": { "line": line, "character": start }, "end": { "line": line, "character": end } })
+    };
+
+    assert_eq!(response(1)["result"]["capabilities"]["textDocumentSync"], 1);
+    let diagnostics: Vec<&Value> = messages.iter().filter(|m| m["method"] == "textDocument/publishDiagnostics").collect();
+    assert_eq!(diagnostics[0]["params"]["diagnostics"], json!([]));
+
+    assert_eq!(response(2)["result"]["range"], range(1, 4, 7));
+    let references = response(3)["result"].clone();
+    let references: Vec<&Value> = references.as_array().unwrap().iter().map(|location| &location["range"]).collect();
+    assert_eq!(references, [&range(0, 4, 9), &range(5, 0, 5), &range(5, 12, 17)]);
+    assert_eq!(response(4)["result"]["contents"]["value"], "```lox\nvar sum\n```\nDeclared on line 3");
+
+    let symbols = response(5)["result"].clone();
+    let names: Vec<(&str, &Value)> =
+        symbols.as_array().unwrap().iter().map(|symbol| (symbol["name"].as_str().unwrap(), &symbol["kind"])).collect();
+    assert_eq!(names, [("total", &json!(13)), ("add", &json!(12)), ("sum", &json!(13))]);
+
+    let inside = labels(&response(6)["result"]);
+    for name in ["total", "add", "a", "b", "sum", "while", "print"] {
+        assert!(inside.contains(&name.to_string()), "{} missing from {:?}", name, inside);
+    }
+    let outside = labels(&response(7)["result"]);
+    assert!(outside.contains(&"add".to_string()));
+    assert!(!outside.contains(&"sum".to_string()) && !outside.contains(&"a".to_string()));
+
+    let resolve_error = &diagnostics[1]["params"]["diagnostics"];
+    assert_eq!(resolve_error[0]["message"], "Can't read local variable in its own initializer.");
+    assert_eq!(resolve_error[0]["range"], range(0, 10, 11));
+    let parse_error = &diagnostics[2]["params"]["diagnostics"];
+    assert_eq!(parse_error.as_array().unwrap().len(), 1);
+    assert_eq!(parse_error[0]["range"], range(1, 9, 10));
+    let warnings = &diagnostics[3]["params"]["diagnostics"];
+    assert_eq!(warnings[0]["message"], "Parameter 'a' is never used.");
+    assert_eq!(warnings[0]["severity"], SEVERITY_WARNING);
+    assert_eq!(warnings[0]["range"], range(2, 8, 9));
+    assert_eq!(response(8)["error"]["code"], METHOD_NOT_FOUND);
+    assert_eq!(response(10)["result"]["contents"]["value"], "```lox\nfun add(a, b)\n```\nAdds `a` and `b`.\nPure.\n\nDeclared on line 3");
+    assert_eq!(response(9)["result"], Value::Null);
+}
+
+#[test]
+fn test_lsp_utf16_positions() {
+    // 😀 is two UTF-16 code units, so columns after it run one ahead of
+    // char indices.
+    let document = Document::analyze("var s = \"😀\"; var 名 = s;".to_string());
+    let reference = &document.symbols.references[0];
+    assert_eq!(document.token_range(&reference.name), json!({ "start": { "line": 0, "character": 22 }, "end": { "line": 0, "character": 23 } }));
+    let declaration = &document.symbols.declarations[1];
+    assert_eq!(document.token_range(&declaration.name)["start"]["character"], 18);
+    let index = document.index(&json!({ "line": 0, "character": 22 }));
+    assert_eq!(document.declaration_at(index), Some(0));
+}
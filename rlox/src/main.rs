@@ -1,20 +1,183 @@
 use std::env::args;
+use std::io::Read;
+use std::rc::Rc;
 
-use jlox_rust::Lox;
+use jlox_rust::debugger::TerminalDebugger;
+use jlox_rust::formatter::format_source;
+use jlox_rust::{AstFormat, Lox};
+
+const USAGE: &str = "Usage: jlox [--time] [--profile[=FILE]] [--coverage[=FILE]] [--debug] [--dump-ast[=sexpr|json]] [--dump-tokens[=trivia]] [script]\n       jlox fmt [--check] [files...]";
+
+// Where `--profile` writes collapsed stacks unless given a file.
+const DEFAULT_PROFILE_OUTPUT: &str = "profile.folded";
+// Where `--coverage` writes the LCOV tracefile unless given a file.
+const DEFAULT_COVERAGE_OUTPUT: &str = "lcov.info";
 
 fn main() {
-    let cmd_args: Vec<String> = args().collect();
+    let cmd_args: Vec<String> = args().skip(1).collect();
+    if cmd_args.first().is_some_and(|arg| arg == "fmt") {
+        std::process::exit(fmt(&cmd_args[1..]));
+    }
+    let (flags, paths): (Vec<&String>, Vec<&String>) = cmd_args.iter().partition(|arg| arg.starts_with("--"));
+    let mut time = false;
+    let mut profile_output = None;
+    let mut coverage_output = None;
+    let mut debug = false;
+    let mut dump_ast = None;
+    let mut dump_tokens = None;
+    for flag in flags {
+        match flag.as_str() {
+            "--time" => time = true,
+            "--profile" => profile_output = Some(DEFAULT_PROFILE_OUTPUT),
+            _ if flag.starts_with("--profile=") => profile_output = Some(&flag["--profile=".len()..]),
+            "--coverage" => coverage_output = Some(DEFAULT_COVERAGE_OUTPUT),
+            _ if flag.starts_with("--coverage=") => coverage_output = Some(&flag["--coverage=".len()..]),
+            "--debug" => debug = true,
+            "--dump-ast" | "--dump-ast=sexpr" => dump_ast = Some(AstFormat::SExpr),
+            "--dump-ast=json" => dump_ast = Some(AstFormat::Json),
+            "--dump-tokens" => dump_tokens = Some(false),
+            "--dump-tokens=trivia" => dump_tokens = Some(true),
+            _ => {
+                println!("{}", USAGE);
+                std::process::exit(64);
+            }
+        }
+    }
+
     let mut lox = Lox::new();
-    if cmd_args.len() > 2 {
-        println!("Usage: jlox [script]");
+    // Modules not found next to the importing file are looked for here.
+    if let Some(dirs) = std::env::var_os("LOX_PATH") {
+        lox.interpreter().set_module_path(std::env::split_paths(&dirs).collect());
+    }
+    if paths.len() > 1 {
+        println!("{}", USAGE);
         // 64 is the exit code for invalid arguments
-        // for arg in cmd_args.iter() {
-        //     println!("{:?}", arg);
-        // }
         std::process::exit(64);
-    } else if cmd_args.len() == 2 {
-        lox.run_file(&cmd_args[1]);
+    } else if let (Some(trivia), [path]) = (dump_tokens, paths.as_slice()) {
+        // Only scan the script, then print its tokens.
+        print!("{}", lox.dump_tokens(&read_source(path), trivia));
+        if lox.had_error {
+            std::process::exit(65);
+        }
+    } else if let (Some(format), [path]) = (dump_ast, paths.as_slice()) {
+        // Only parse and resolve the script, then print its tree.
+        match lox.dump_ast(&read_source(path), format) {
+            Some(dump) => print!("{}", dump),
+            None => std::process::exit(65),
+        }
+    } else if paths.len() == 1 {
+        if profile_output.is_some() {
+            lox.interpreter().start_profiling();
+        }
+        if coverage_output.is_some() {
+            lox.interpreter().start_coverage();
+        }
+        if debug {
+            let source = std::fs::read_to_string(paths[0]).unwrap_or_default();
+            let debugger = TerminalDebugger::new(paths[0], &source);
+            lox.interpreter().set_debug_hook(Some(Rc::new(debugger)));
+        }
+        lox.run_file(paths[0]);
+        if time {
+            eprintln!("{}", lox.timings);
+        }
+        if let (Some(output), Some(profile)) = (profile_output, lox.interpreter().finish_profiling()) {
+            eprintln!("{}", profile);
+            if let Err(err) = std::fs::write(output, profile.collapsed()) {
+                eprintln!("Could not write {}: {}", output, err);
+            } else {
+                eprintln!("Collapsed stacks written to {}", output);
+            }
+        }
+        if let (Some(output), Some(coverage)) = (coverage_output, lox.interpreter().finish_coverage()) {
+            eprintln!("{}", coverage);
+            if let Err(err) = std::fs::write(output, coverage.lcov()) {
+                eprintln!("Could not write {}: {}", output, err);
+            } else {
+                eprintln!("LCOV written to {}", output);
+            }
+        }
+        if lox.had_error {
+            std::process::exit(65);
+        }
+        if lox.had_runtime_error {
+            std::process::exit(70);
+        }
     } else {
         lox.run_prompt();
     }
 }
+
+fn read_source(path: &str) -> String {
+    std::fs::read_to_string(path).unwrap_or_else(|err| {
+        eprintln!("Could not read {}: {}", path, err);
+        std::process::exit(66);
+    })
+}
+
+// `jlox fmt`: rewrites each file in canonical layout, or with `--check` only
+// lists the ones that would change. Without files it filters stdin to
+// stdout. Returns the exit code.
+fn fmt(args: &[String]) -> i32 {
+    let (flags, paths): (Vec<&String>, Vec<&String>) = args.iter().partition(|arg| arg.starts_with("--"));
+    let mut check = false;
+    for flag in flags {
+        match flag.as_str() {
+            "--check" => check = true,
+            _ => {
+                println!("{}", USAGE);
+                return 64;
+            }
+        }
+    }
+
+    if paths.is_empty() {
+        let mut source = String::new();
+        if let Err(err) = std::io::stdin().read_to_string(&mut source) {
+            eprintln!("Could not read stdin: {}", err);
+            return 74;
+        }
+        return match format_source(&source) {
+            Ok(formatted) if check => i32::from(formatted != source),
+            Ok(formatted) => {
+                print!("{}", formatted);
+                0
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                65
+            }
+        };
+    }
+
+    let mut status = 0;
+    for path in paths {
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("Could not read {}: {}", path, err);
+                status = 74;
+                continue;
+            }
+        };
+        let formatted = match format_source(&source) {
+            Ok(formatted) => formatted,
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                status = 65;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{}", path);
+            status = status.max(1);
+        } else if let Err(err) = std::fs::write(path, formatted) {
+            eprintln!("Could not write {}: {}", path, err);
+            status = 74;
+        }
+    }
+    status
+}
@@ -0,0 +1,135 @@
+use std::cell::Cell;
+use std::mem::size_of;
+
+use crate::ast::LoxValue;
+use crate::environment::Slot;
+use crate::errors::RuntimeError;
+use crate::function::LoxFunction;
+use crate::interner::{Symbol, SymbolMap};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    // Bytes charged over the whole run.
+    pub allocated: usize,
+    // Bytes charged and not yet released.
+    pub live: usize,
+    // Highest value `live` has reached.
+    pub peak: usize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub collections: usize,
+    pub scopes_freed: usize,
+    pub bytes_reclaimed: usize,
+    // Scopes that survived the most recent collection.
+    pub live_scopes: usize,
+}
+
+// Byte accounting for script-visible allocations: strings, scopes,
+// bindings and function objects. The sizes are estimates of what the
+// interpreter keeps alive, not exact allocator figures.
+#[derive(Default)]
+pub struct MemoryAccount {
+    stats: Cell<MemoryStats>,
+    limit: Cell<Option<usize>>,
+    since_settled: Cell<usize>,
+}
+
+impl MemoryAccount {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_limit(&self, limit: Option<usize>) {
+        self.limit.set(limit);
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit.get()
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        self.stats.get()
+    }
+
+    // Records `bytes` as live, failing without charging anything if that
+    // would take live usage over the limit.
+    pub fn charge(&self, bytes: usize) -> Result<(), RuntimeError> {
+        let mut stats = self.stats.get();
+        let live = stats.live.saturating_add(bytes);
+        if let Some(limit) = self.limit.get() {
+            if live > limit {
+                return Err(RuntimeError::bare("Out of memory."));
+            }
+        }
+        stats.allocated = stats.allocated.saturating_add(bytes);
+        stats.live = live;
+        stats.peak = stats.peak.max(live);
+        self.stats.set(stats);
+        self.since_settled.set(self.since_settled.get().saturating_add(bytes));
+        Ok(())
+    }
+
+    // Bytes charged since the last call to `settle`.
+    pub fn since_settled(&self) -> usize {
+        self.since_settled.get()
+    }
+
+    // Replaces the running estimate with the bytes a collection found
+    // reachable. Returns how many bytes that released.
+    pub fn settle(&self, reachable: usize) -> usize {
+        let mut stats = self.stats.get();
+        let released = stats.live.saturating_sub(reachable);
+        stats.live = reachable;
+        self.stats.set(stats);
+        self.since_settled.set(0);
+        released
+    }
+
+    pub fn reset(&self) {
+        self.stats.set(MemoryStats::default());
+        self.since_settled.set(0);
+    }
+}
+
+// A string value: the Rc allocation's two counts and its bytes.
+pub fn string_size(s: &str) -> usize {
+    2 * size_of::<usize>() + s.len()
+}
+
+// Names are interned, so a binding only costs its slot.
+pub fn binding_size() -> usize {
+    size_of::<Symbol>() + size_of::<Slot>()
+}
+
+pub fn scope_size() -> usize {
+    size_of::<SymbolMap<Slot>>() + size_of::<usize>()
+}
+
+pub fn function_size() -> usize {
+    2 * size_of::<usize>() + size_of::<LoxFunction>()
+}
+
+// Footprint of a value held in a scope, as charged when it was created.
+pub fn value_size(value: &LoxValue) -> usize {
+    match value {
+        LoxValue::String(s) => string_size(s),
+        LoxValue::Callable(_) => function_size(),
+        _ => 0,
+    }
+}
+
+#[test]
+fn test_memory_limit() {
+    let account = MemoryAccount::new();
+    account.set_limit(Some(100));
+    account.charge(60).unwrap();
+    assert!(account.charge(50).is_err());
+    account.charge(40).unwrap();
+
+    let stats = account.stats();
+    assert_eq!(stats.allocated, 100);
+    assert_eq!(stats.live, 100);
+    assert_eq!(stats.peak, 100);
+}
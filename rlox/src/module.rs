@@ -0,0 +1,125 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use crate::ast::{LoxValue, RcStmt, Stmt};
+use crate::environment::Environment;
+use crate::interner::Symbol;
+
+// A loaded module: the scope its top level ran in and the names it
+// exports. Exports are read from that scope on each access, so an
+// importer sees later assignments made by the module's own functions.
+pub struct Module {
+    // The path it was found at, for messages.
+    pub name: String,
+    scope: usize,
+    exports: HashSet<Symbol>,
+}
+
+impl Module {
+    pub(crate) fn new(name: String, scope: usize, stmts: &[RcStmt]) -> Self {
+        let exports = stmts
+            .iter()
+            .filter_map(|stmt| match stmt.as_ref() {
+                Stmt::Export(export) => match export.declaration.as_ref() {
+                    Stmt::Var(var) => Some(var.name.symbol),
+                    Stmt::Function(function) => Some(function.name.symbol),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        Module { name, scope, exports }
+    }
+
+    pub fn scope(&self) -> usize {
+        self.scope
+    }
+
+    // The current value of export `name`, or `None` if it has no such
+    // export.
+    pub fn get(&self, environment: &Environment, name: Symbol) -> Option<LoxValue> {
+        self.exports.contains(&name).then(|| environment.get_in(self.scope, name)).flatten()
+    }
+}
+
+impl Display for Module {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<module {}>", self.name)
+    }
+}
+
+// Finds, caches and tracks the loading of modules for one interpreter.
+pub(crate) struct ModuleLoader {
+    // Directories tried, in order, when a path is not found next to the
+    // importing file.
+    pub search_path: Vec<PathBuf>,
+    // Keyed by canonical path, so each file is evaluated once however it
+    // is reached.
+    cache: HashMap<PathBuf, Rc<Module>>,
+    // Files whose top level is running, outermost first, by canonical path
+    // and the path they were found at. The script itself, when known, is
+    // first.
+    loading: Vec<(PathBuf, PathBuf)>,
+    // Path of each file by id, as found. The script is file 0, with an
+    // empty path until `set_script`.
+    paths: Vec<PathBuf>,
+}
+
+impl Default for ModuleLoader {
+    fn default() -> Self {
+        Self { search_path: vec![], cache: HashMap::new(), loading: vec![], paths: vec![PathBuf::new()] }
+    }
+}
+
+impl ModuleLoader {
+    pub fn set_script(&mut self, path: &Path) {
+        self.loading = vec![(canonical(path), path.to_path_buf())];
+        self.paths[0] = path.to_path_buf();
+    }
+
+    // Where the tokens of `file` were read from, if known.
+    pub fn path(&self, file: usize) -> Option<&Path> {
+        self.paths.get(file).map(PathBuf::as_path).filter(|path| !path.as_os_str().is_empty())
+    }
+
+    // Where `path`, imported from file `importer`, refers to: relative to
+    // the importing file first, then to each search path entry. The
+    // importer may have finished loading long ago, e.g. when the import is
+    // in one of its functions.
+    pub fn locate(&self, path: &str, importer: usize) -> Option<PathBuf> {
+        let requested = Path::new(path);
+        let importer = self.path(importer).and_then(Path::parent);
+        let base = importer.map(Path::to_path_buf).unwrap_or_default();
+        std::iter::once(base).chain(self.search_path.iter().cloned()).map(|dir| dir.join(requested)).find(|file| file.is_file())
+    }
+
+    pub fn cached(&self, file: &Path) -> Option<Rc<Module>> {
+        self.cache.get(&canonical(file)).cloned()
+    }
+
+    // Starts loading `file`, or describes the import cycle it would close.
+    pub fn begin(&mut self, file: &Path) -> Result<usize, String> {
+        let key = canonical(file);
+        if let Some(start) = self.loading.iter().position(|(loading, _)| *loading == key) {
+            let names: Vec<String> =
+                self.loading[start..].iter().map(|(_, found)| found.as_path()).chain([file]).map(|path| path.display().to_string()).collect();
+            return Err(format!("Import cycle: {}.", names.join(" -> ")));
+        }
+        self.loading.push((key, file.to_path_buf()));
+        self.paths.push(file.to_path_buf());
+        Ok(self.paths.len() - 1)
+    }
+
+    // Finishes loading the innermost file, caching it if it ran.
+    pub fn end(&mut self, module: Option<Rc<Module>>) {
+        if let (Some((key, _)), Some(module)) = (self.loading.pop(), module) {
+            self.cache.insert(key, module);
+        }
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
@@ -0,0 +1,169 @@
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::rc::Rc;
+
+use crate::ast::LoxValue;
+
+// A value packed into a single 64-bit word. Numbers are stored as their own
+// bits; nil, booleans and heap values live in the payload of a quiet NaN
+// that arithmetic never produces, so a scope slot is 8 bytes instead of a
+// full `LoxValue`. Integers that fit in 32 bits sit in the low half of a
+// NaN tagged with bit 48. Strings, callables and larger integers are boxed
+// once more behind an `Rc<LoxValue>` whose (thin) pointer fits in the low
+// 48 bits.
+pub struct NanBox {
+    bits: u64,
+    // Holds an `Rc` when tagged as an object, so must not cross threads.
+    _owner: PhantomData<Rc<LoxValue>>,
+}
+
+const SIGN_BIT: u64 = 0x8000_0000_0000_0000;
+const QNAN: u64 = 0x7ffc_0000_0000_0000;
+
+const TAG_NIL: u64 = 1;
+const TAG_FALSE: u64 = 2;
+const TAG_TRUE: u64 = 3;
+
+const NIL: u64 = QNAN | TAG_NIL;
+const FALSE: u64 = QNAN | TAG_FALSE;
+const TRUE: u64 = QNAN | TAG_TRUE;
+const OBJECT: u64 = SIGN_BIT | QNAN;
+const INT: u64 = QNAN | 0x0001_0000_0000_0000;
+const INT_MASK: u64 = SIGN_BIT | INT;
+
+impl NanBox {
+    fn from_bits(bits: u64) -> Self {
+        NanBox { bits, _owner: PhantomData }
+    }
+
+    pub fn number(n: f64) -> Self {
+        // Every NaN is stored as the canonical one so none collide with a tag.
+        let n = if n.is_nan() { f64::NAN } else { n };
+        Self::from_bits(n.to_bits())
+    }
+
+    pub fn bool(b: bool) -> Self {
+        Self::from_bits(if b { TRUE } else { FALSE })
+    }
+
+    pub fn int(n: i64) -> Self {
+        match i32::try_from(n) {
+            Ok(n) => Self::from_bits(INT | n as u32 as u64),
+            Err(_) => Self::object(LoxValue::Int(n)),
+        }
+    }
+
+    pub fn nil() -> Self {
+        Self::from_bits(NIL)
+    }
+
+    fn object(value: LoxValue) -> Self {
+        let ptr = Rc::into_raw(Rc::new(value)) as u64;
+        assert_eq!(ptr & OBJECT, 0, "pointer does not fit in a NaN payload");
+        Self::from_bits(OBJECT | ptr)
+    }
+
+    pub fn is_number(&self) -> bool {
+        self.bits & QNAN != QNAN
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        self.is_number().then(|| f64::from_bits(self.bits))
+    }
+
+    fn is_object(&self) -> bool {
+        self.bits & OBJECT == OBJECT
+    }
+
+    fn as_ptr(&self) -> *const LoxValue {
+        (self.bits & !OBJECT) as *const LoxValue
+    }
+
+    // The boxed value, cloned out of the word.
+    pub fn get(&self) -> LoxValue {
+        if self.is_number() {
+            return LoxValue::Number(f64::from_bits(self.bits));
+        }
+        if self.bits & INT_MASK == INT {
+            return LoxValue::Int(self.bits as u32 as i32 as i64);
+        }
+        match self.bits {
+            NIL => LoxValue::Nil,
+            FALSE => LoxValue::Bool(false),
+            TRUE => LoxValue::Bool(true),
+            // SAFETY: object words are only built by `object` and own one
+            // strong count of the pointee until dropped.
+            _ => unsafe { (*self.as_ptr()).clone() },
+        }
+    }
+}
+
+impl From<LoxValue> for NanBox {
+    fn from(value: LoxValue) -> Self {
+        match value {
+            LoxValue::Number(n) => Self::number(n),
+            LoxValue::Int(n) => Self::int(n),
+            LoxValue::Bool(b) => Self::bool(b),
+            LoxValue::Nil => Self::nil(),
+            value => Self::object(value),
+        }
+    }
+}
+
+impl Clone for NanBox {
+    fn clone(&self) -> Self {
+        if self.is_object() {
+            // SAFETY: see `get`.
+            unsafe { Rc::increment_strong_count(self.as_ptr()) };
+        }
+        Self::from_bits(self.bits)
+    }
+}
+
+impl Drop for NanBox {
+    fn drop(&mut self) {
+        if self.is_object() {
+            // SAFETY: see `get`.
+            unsafe { Rc::decrement_strong_count(self.as_ptr()) };
+        }
+    }
+}
+
+impl Debug for NanBox {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NanBox({:?})", self.get())
+    }
+}
+
+#[test]
+fn test_nanbox_roundtrip() {
+    let values = [
+        LoxValue::Number(1.5),
+        LoxValue::Number(-0.0),
+        LoxValue::Number(f64::INFINITY),
+        LoxValue::Int(0),
+        LoxValue::Int(-7),
+        LoxValue::Int(i32::MAX as i64),
+        LoxValue::Int(i64::MIN),
+        LoxValue::Bool(true),
+        LoxValue::Bool(false),
+        LoxValue::Nil,
+        LoxValue::String("boxed".into()),
+    ];
+    for value in values {
+        assert_eq!(NanBox::from(value.clone()).get(), value);
+    }
+    assert!(NanBox::number(f64::NAN).as_number().unwrap().is_nan());
+    assert_eq!(std::mem::size_of::<NanBox>(), 8);
+}
+
+#[test]
+fn test_nanbox_refcount() {
+    let s: Rc<str> = "shared".into();
+    let boxed = NanBox::from(LoxValue::String(s.clone()));
+    let copy = boxed.clone();
+    drop(boxed);
+    assert_eq!(copy.get(), LoxValue::String(s.clone()));
+    drop(copy);
+    assert_eq!(Rc::strong_count(&s), 1);
+}
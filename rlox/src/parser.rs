@@ -1,4 +1,6 @@
 use std::cell::Cell;
+use std::collections::HashMap;
+use std::rc::Rc;
 use std::vec;
 
 use crate::ast::*;
@@ -12,24 +14,51 @@ use crate::errors::ParseError;
 pub struct Parser {
     current: Cell<usize>,
     tokens: Vec<Token>,
+    // Doc comment text by the index of the token it precedes.
+    docs: HashMap<usize, Rc<str>>,
 }
 
 type ParseResult<T> = Result<T, ParseError>;
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
+        let mut code = Vec::with_capacity(tokens.len());
+        let mut docs = HashMap::new();
+        let mut lines: Vec<String> = vec![];
+        for token in tokens {
+            if token._type == TokenType::DOCCOMMENT {
+                let text = token.lexeme.strip_prefix("///").unwrap_or(&token.lexeme);
+                lines.push(text.strip_prefix(' ').unwrap_or(text).trim_end().to_string());
+                continue;
+            }
+            if !lines.is_empty() {
+                docs.insert(code.len(), lines.join("\n").into());
+                lines.clear();
+            }
+            code.push(token);
+        }
         Self {
             current: Cell::new(0),
-            tokens,
+            tokens: code,
+            docs,
         }
     }
     pub fn parse(self) -> ParseResult<Vec<RcStmt>> {
         let mut stmts = vec![];
         while !self._is_end() {
-            let stmt = self.declaration()?;
+            let stmt = if self._match(&[TokenType::EXPORT]) { self.export()? } else { self.declaration()? };
             stmts.push(stmt);
         }
-        return Ok(stmts);
+        Ok(stmts)
+    }
+
+    // Parses a lone expression, such as one typed at the debugger prompt.
+    pub fn parse_expression(self) -> ParseResult<RcExpr> {
+        let expr = self.expression()?;
+        if !self._is_end() {
+            return Err(ParseError::new(self._peek(), "Expect end of expression."));
+        }
+        Ok(expr)
     }
 
     fn declaration(&self) -> ParseResult<RcStmt> {
@@ -39,10 +68,56 @@ impl Parser {
         if self._match(&[TokenType::VAR]) {
             return self.var_declaration();
         }
+        if self._match(&[TokenType::CONST]) {
+            return self.const_declaration();
+        }
+        if self._match(&[TokenType::IMPORT]) {
+            return self.import();
+        }
+        if self._check(&TokenType::EXPORT) {
+            return Err(ParseError::new(self._peek(), "Can only export top-level declarations."));
+        }
         self.statement()
     }
 
+    // `export` has been consumed.
+    fn export(&self) -> ParseResult<RcStmt> {
+        let keyword = self._previous().unwrap().clone();
+        let declaration = if self._match(&[TokenType::FUN]) {
+            self.function("function")?
+        } else if self._match(&[TokenType::VAR]) {
+            self.var_declaration()?
+        } else if self._match(&[TokenType::CONST]) {
+            self.const_declaration()?
+        } else {
+            return Err(ParseError::new(self._peek(), "Expect 'fun', 'var' or 'const' after 'export'."));
+        };
+        Ok(Export::build(keyword, declaration))
+    }
+
+    // `import "path" as name;`, where `as` is only a keyword here.
+    fn import(&self) -> ParseResult<RcStmt> {
+        let keyword = self._previous().unwrap().clone();
+        let path = self._consume(&TokenType::STRING, "Expect module path string after 'import'.")?.clone();
+        if !self._peek().is_some_and(|token| token._type == TokenType::IDENTIFIER && token.lexeme.as_ref() == "as") {
+            return Err(ParseError::new(self._peek(), "Expect 'as' after module path."));
+        }
+        self._advance();
+        let name = self._consume(&TokenType::IDENTIFIER, "Expect module name after 'as'.")?.clone();
+        self._consume(&TokenType::SEMICOLON, "Expect ';' after import.")?;
+        Ok(Import::build(keyword, path, name))
+    }
+
+    // The doc comment above the token just consumed, or above the `export`
+    // before it.
+    fn doc(&self) -> Option<Rc<str>> {
+        let index = self.current.get() - 1;
+        let exported = index > 0 && self.tokens[index - 1]._type == TokenType::EXPORT;
+        self.docs.get(&index).or_else(|| exported.then(|| self.docs.get(&(index - 1))).flatten()).cloned()
+    }
+
     fn function(&self, kind: &str) -> ParseResult<RcStmt> {
+        let doc = self.doc();
         let name = self._consume(&TokenType::IDENTIFIER, &format!("Expect {} name.", kind))?;
         self._consume(&TokenType::LEFTPAREN, &format!("Expect '(' after {} name.", kind))?;
         let mut params = vec![];
@@ -61,10 +136,11 @@ impl Parser {
         self._consume(&TokenType::RIGHTPAREN, "Expect ')' after parameters.")?;
         self._consume(&TokenType::LEFTBRACE, &format!("Expect '{{' before {} body.", kind))?;
         let body = self.block()?;
-        return Ok(Function::build(name.clone(), params, body));
+        Ok(Function::build(name.clone(), params, body, doc))
     }
 
     fn var_declaration(&self) -> ParseResult<RcStmt> {
+        let doc = self.doc();
         let name = self._consume(&TokenType::IDENTIFIER, "Expect variable name.")?;
         let initializer = if self._match(&[TokenType::EQUAL]) {
             Some(self.expression()?)
@@ -75,7 +151,16 @@ impl Parser {
             &TokenType::SEMICOLON,
             "Expect ';' after variable declaration.",
         )?;
-        return Ok(Var::build(name.clone(), initializer));
+        Ok(Var::build(name.clone(), initializer, doc, false))
+    }
+
+    fn const_declaration(&self) -> ParseResult<RcStmt> {
+        let doc = self.doc();
+        let name = self._consume(&TokenType::IDENTIFIER, "Expect constant name.")?.clone();
+        self._consume(&TokenType::EQUAL, "Expect '=' after constant name.")?;
+        let initializer = self.expression()?;
+        self._consume(&TokenType::SEMICOLON, "Expect ';' after constant declaration.")?;
+        Ok(Var::build(name, Some(initializer), doc, true))
     }
 
     fn statement(&self) -> ParseResult<RcStmt> {
@@ -97,10 +182,11 @@ impl Parser {
         if self._match(&[TokenType::LEFTBRACE]) {
             return self.block();
         }
-        return self.expression_statement();
+        self.expression_statement()
     }
 
     fn for_statement(&self) -> ParseResult<RcStmt> {
+        let keyword = self._previous().unwrap().clone();
         self._consume(&TokenType::LEFTPAREN, "Expect '(' after 'for'.")?;
 
         let initializer = if self._match(&[TokenType::SEMICOLON]) {
@@ -128,21 +214,22 @@ impl Parser {
         let mut body = self.statement()?;
         
         if let Some(increment) = increment {
-            body = Block::build(vec![body, Expression::build(increment)]);
+            body = Block::build(vec![body, Expression::build(increment)], None);
         }
         if let Some(condition) = condition {
-            body = While::build(condition, body);
+            body = While::build(keyword, condition, body);
         } else {
-            body = While::build(Literal::build(TokenLiteral::Bool(true)), body);
+            body = While::build(keyword, Literal::build(TokenLiteral::Bool(true), None), body);
         }
         if let Some(initializer) = initializer {
-            body = Block::build(vec![initializer, body]);
+            body = Block::build(vec![initializer, body], None);
         }
         
-        return Ok(body);
+        Ok(body)
     }
 
     fn if_statement(&self) -> ParseResult<RcStmt> {
+        let keyword = self._previous().unwrap().clone();
         self._consume(&TokenType::LEFTPAREN, "Expect '(' after 'if'.")?;
         let condition = self.expression()?;
         self._consume(&TokenType::RIGHTPAREN, "Expect ')' after if condition.")?;
@@ -152,13 +239,14 @@ impl Parser {
         } else {
             None
         };
-        return Ok(If::build(condition, then_branch, else_branch));
+        Ok(If::build(keyword, condition, then_branch, else_branch))
     }
 
     fn print_statement(&self) -> ParseResult<RcStmt> {
+        let keyword = self._previous().unwrap().clone();
         let value = self.expression()?;
         self._consume(&TokenType::SEMICOLON, "Expect ';' after value.")?;
-        return Ok(Print::build(value));
+        Ok(Print::build(keyword, value))
     }
 
     fn return_statement(&self) -> ParseResult<RcStmt> {
@@ -169,39 +257,41 @@ impl Parser {
             None
         };
         self._consume(&TokenType::SEMICOLON, "Expect ';' after return value.")?;
-        return Ok(Return::build(keyword.clone(), value));
+        Ok(Return::build(keyword.clone(), value))
     }
     
     fn while_statement(&self) -> ParseResult<RcStmt> {
+        let keyword = self._previous().unwrap().clone();
         self._consume(&TokenType::LEFTPAREN, "Expect '(' after 'while'.")?;
         let condition = self.expression()?;
         self._consume(&TokenType::RIGHTPAREN, "Expect ')' after condition.")?;
         let body = self.statement()?;
-        return Ok(While::build(condition, body));
+        Ok(While::build(keyword, condition, body))
     }
 
 
     fn block(&self) -> ParseResult<RcStmt> {
+        let brace = self._previous().cloned();
         let mut statements = vec![];
         while !self._check(&TokenType::RIGHTBRACE) && !self._is_end() {
             statements.push(self.declaration()?);
         }
         self._consume(&TokenType::RIGHTBRACE, "Expect '}' after block.")?;
-        return Ok(Block::build(statements));
+        Ok(Block::build(statements, brace))
     }
 
     fn expression_statement(&self) -> ParseResult<RcStmt> {
         let value = self.expression()?;
         self._consume(&TokenType::SEMICOLON, "Expect ';' after value.")?;
-        return Ok(Expression::build(value));
+        Ok(Expression::build(value))
     }
 
     fn expression(&self) -> ParseResult<RcExpr> {
-        return self.assignment();
+        self.assignment()
     }
 
     fn assignment(&self) -> ParseResult<RcExpr> {
-        let expr = self.or()?;
+        let expr = self.conditional()?;
         if self._match(&[TokenType::EQUAL]) {
             let equals = self._previous().unwrap();
             let value = self.assignment()?;
@@ -211,7 +301,39 @@ impl Parser {
             }
             return Err(ParseError::new(Some(equals), "Invalid assignment target."));
         }
-        return Ok(expr);
+        if self._match(&[
+            TokenType::PLUSEQUAL,
+            TokenType::MINUSEQUAL,
+            TokenType::STAREQUAL,
+            TokenType::SLASHEQUAL,
+            TokenType::PERCENTEQUAL,
+        ]) {
+            let operator = self._previous().unwrap();
+            let value = self.assignment()?;
+            return self.update(expr, operator.clone(), value, false);
+        }
+        Ok(expr)
+    }
+
+    // Only variables can be updated in place.
+    fn update(&self, target: RcExpr, operator: Token, value: RcExpr, postfix: bool) -> ParseResult<RcExpr> {
+        if let Expr::Variable(x) = target.as_ref() {
+            return Ok(Update::build(x.name.clone(), operator, value, postfix));
+        }
+        Err(ParseError::new(Some(&operator), "Invalid assignment target."))
+    }
+
+    // `a ? b : c ? d : e` groups to the right, as in C.
+    fn conditional(&self) -> ParseResult<RcExpr> {
+        let condition = self.or()?;
+        if self._match(&[TokenType::QUESTION]) {
+            let question = self._previous().unwrap();
+            let then_branch = self.expression()?;
+            self._consume(&TokenType::COLON, "Expect ':' after then branch of conditional expression.")?;
+            let else_branch = self.conditional()?;
+            return Ok(Conditional::build(condition, question.clone(), then_branch, else_branch));
+        }
+        Ok(condition)
     }
 
     fn or(&self) -> ParseResult<RcExpr> {
@@ -221,7 +343,7 @@ impl Parser {
             let right = self.and()?;
             expr = Logical::build(expr, operator.clone(), right);
         }
-        return Ok(expr);
+        Ok(expr)
     }
 
     fn and(&self) -> ParseResult<RcExpr> {
@@ -231,23 +353,23 @@ impl Parser {
             let right = self.equality()?;
             expr = Logical::build(expr, operator.clone(), right);
         }
-        return Ok(expr);
+        Ok(expr)
     }
 
     fn equality(&self) -> ParseResult<RcExpr> {
-        let mut expr = self.comparison().unwrap();
+        let mut expr = self.comparison()?;
 
         while self._match(&[TokenType::BANGEQUAL, TokenType::EQUALEQUAL]) {
             let operator = self._previous().unwrap();
-            let right = self.comparison().unwrap();
+            let right = self.comparison()?;
             expr = Binary::build(expr, operator.clone(), right);
         }
 
-        return Ok(expr);
+        Ok(expr)
     }
 
     fn comparison(&self) -> ParseResult<RcExpr> {
-        let mut expr = self.term().unwrap();
+        let mut expr = self.bit_or()?;
         while self._match(&[
             TokenType::GREATER,
             TokenType::GREATEREQUAL,
@@ -255,39 +377,107 @@ impl Parser {
             TokenType::LESSEQUAL,
         ]) {
             let operator = self._previous().unwrap();
-            let right = self.term().unwrap();
+            let right = self.bit_or()?;
+            expr = Binary::build(expr, operator.clone(), right);
+        }
+        Ok(expr)
+    }
+
+    // The bitwise operators bind tighter than comparisons, unlike C, so
+    // `x & 1 == 0` means `(x & 1) == 0`.
+    fn bit_or(&self) -> ParseResult<RcExpr> {
+        let mut expr = self.bit_xor()?;
+        while self._match(&[TokenType::PIPE]) {
+            let operator = self._previous().unwrap();
+            let right = self.bit_xor()?;
             expr = Binary::build(expr, operator.clone(), right);
         }
-        return Ok(expr);
+        Ok(expr)
+    }
+
+    fn bit_xor(&self) -> ParseResult<RcExpr> {
+        let mut expr = self.bit_and()?;
+        while self._match(&[TokenType::CARET]) {
+            let operator = self._previous().unwrap();
+            let right = self.bit_and()?;
+            expr = Binary::build(expr, operator.clone(), right);
+        }
+        Ok(expr)
+    }
+
+    fn bit_and(&self) -> ParseResult<RcExpr> {
+        let mut expr = self.shift()?;
+        while self._match(&[TokenType::AMPERSAND]) {
+            let operator = self._previous().unwrap();
+            let right = self.shift()?;
+            expr = Binary::build(expr, operator.clone(), right);
+        }
+        Ok(expr)
+    }
+
+    fn shift(&self) -> ParseResult<RcExpr> {
+        let mut expr = self.term()?;
+        while self._match(&[TokenType::LESSLESS, TokenType::GREATERGREATER]) {
+            let operator = self._previous().unwrap();
+            let right = self.term()?;
+            expr = Binary::build(expr, operator.clone(), right);
+        }
+        Ok(expr)
     }
 
     fn term(&self) -> ParseResult<RcExpr> {
-        let mut expr = self.factor().unwrap();
+        let mut expr = self.factor()?;
         while self._match(&[TokenType::MINUS, TokenType::PLUS]) {
             let operator = self._previous().unwrap();
-            let right = self.factor().unwrap();
+            let right = self.factor()?;
             expr = Binary::build(expr, operator.clone(), right);
         }
-        return Ok(expr);
+        Ok(expr)
     }
 
     fn factor(&self) -> ParseResult<RcExpr> {
         let mut expr = self.unary()?;
-        while self._match(&[TokenType::SLASH, TokenType::STAR]) {
+        while self._match(&[TokenType::SLASH, TokenType::STAR, TokenType::PERCENT, TokenType::TILDESLASH]) {
             let operator = self._previous().unwrap();
             let right = self.unary()?;
             expr = Binary::build(expr, operator.clone(), right);
         }
-        return Ok(expr);
+        Ok(expr)
     }
 
     fn unary(&self) -> ParseResult<RcExpr> {
-        if self._match(&[TokenType::BANG, TokenType::MINUS]) {
+        if self._match(&[TokenType::BANG, TokenType::MINUS, TokenType::TILDE]) {
             let operator = self._previous().unwrap();
             let right = self.unary()?;
             return Ok(Unary::build(operator.clone(), right));
         }
-        return self.call();
+        if self._match(&[TokenType::PLUSPLUS, TokenType::MINUSMINUS]) {
+            let operator = self._previous().unwrap();
+            let target = self.unary()?;
+            return self.update(target, operator.clone(), one(), false);
+        }
+        self.power()
+    }
+
+    // Right-associative and tighter than a prefix operator on its left, so
+    // `-2 ** 2` is -4, but the exponent may carry its own: `2 ** -1`.
+    fn power(&self) -> ParseResult<RcExpr> {
+        let expr = self.postfix()?;
+        if self._match(&[TokenType::STARSTAR]) {
+            let operator = self._previous().unwrap();
+            let right = self.unary()?;
+            return Ok(Binary::build(expr, operator.clone(), right));
+        }
+        Ok(expr)
+    }
+
+    fn postfix(&self) -> ParseResult<RcExpr> {
+        let expr = self.call()?;
+        if self._match(&[TokenType::PLUSPLUS, TokenType::MINUSMINUS]) {
+            let operator = self._previous().unwrap();
+            return self.update(expr, operator.clone(), one(), true);
+        }
+        Ok(expr)
     }
 
     fn call(&self) -> ParseResult<RcExpr> {
@@ -295,11 +485,14 @@ impl Parser {
         loop {
             if self._match(&[TokenType::LEFTPAREN]) {
                 expr = self.finish_call(expr)?;
+            } else if self._match(&[TokenType::DOT]) {
+                let name = self._consume(&TokenType::IDENTIFIER, "Expect property name after '.'.")?;
+                expr = Get::build(expr, name.clone());
             } else {
                 break;
             }
         }
-        return Ok(expr);
+        Ok(expr)
     }
 
     fn finish_call(&self, callee: RcExpr) -> ParseResult<RcExpr> {
@@ -309,29 +502,32 @@ impl Parser {
                 if arguments.len() >= 255 {
                     return Err(ParseError::new(self._peek(), "Cannot have more than 255 arguments."));
                 }
-                arguments.push(self.expression().unwrap());
+                arguments.push(self.expression()?);
                 if !self._match(&[TokenType::COMMA]) {
                     break;
                 }
             }
         }
         let paren = self._consume(&TokenType::RIGHTPAREN, "Expect ')' after arguments.")?;
-        return Ok(Call::build(callee, paren.clone(), arguments));
+        Ok(Call::build(callee, paren.clone(), arguments))
     }
 
     fn primary(&self) -> ParseResult<RcExpr> {
         if self._match(&[TokenType::FALSE]) {
-            return Ok(Literal::build(TokenLiteral::Bool(false)));
+            return Ok(Literal::build(TokenLiteral::Bool(false), self._previous().cloned()));
         }
         if self._match(&[TokenType::TRUE]) {
-            return Ok(Literal::build(TokenLiteral::Bool(true)));
+            return Ok(Literal::build(TokenLiteral::Bool(true), self._previous().cloned()));
         }
         if self._match(&[TokenType::NIL]) {
-            return Ok(Literal::build(TokenLiteral::Nil));
+            return Ok(Literal::build(TokenLiteral::Nil, self._previous().cloned()));
+        }
+        if self._match(&[TokenType::INTERPOLATION]) {
+            return self.interpolation();
         }
         if self._match(&[TokenType::NUMBER, TokenType::STRING]) {
             if let Some(token) = self._previous() {
-                return Ok(Literal::build(token.literal.clone()));
+                return Ok(Literal::build(token.literal.clone(), Some(token.clone())));
             }
         }
         if self._match(&[TokenType::IDENTIFIER]) {
@@ -347,6 +543,36 @@ impl Parser {
         Err(ParseError::new(self._peek(), "Expect expression."))
     }
 
+    // `"a ${b} c"` is lowered to `"a " + str(b) + " c"`, where `str` is a
+    // STRINGIFY unary that converts any value to a string.
+    fn interpolation(&self) -> ParseResult<RcExpr> {
+        let start = self._previous().unwrap().clone();
+        let mut expr = None;
+        let mut part = start.clone();
+        loop {
+            let concat = |left: Option<RcExpr>, right: RcExpr| match left {
+                Some(left) => Binary::build(left, Token::build(TokenType::PLUS, "+", TokenLiteral::Nil, part.line, part.offset), right),
+                None => right,
+            };
+            if let TokenLiteral::String(text) = &part.literal {
+                if !text.is_empty() {
+                    expr = Some(concat(expr, Literal::build(part.literal.clone(), Some(part.clone()))));
+                }
+            }
+            if part._type == TokenType::STRING {
+                break;
+            }
+            let value = self.expression()?;
+            let stringify = Token::build(TokenType::STRINGIFY, "str", TokenLiteral::Nil, part.line, part.offset);
+            expr = Some(concat(expr, Unary::build(stringify, value)));
+            if !self._match(&[TokenType::INTERPOLATION, TokenType::STRING]) {
+                return Err(ParseError::new(self._peek(), "Expect '}' after interpolated expression."));
+            }
+            part = self._previous().unwrap().clone();
+        }
+        Ok(expr.unwrap_or_else(|| Literal::build(start.literal.clone(), Some(start))))
+    }
+
     fn _match(&self, types: &[TokenType]) -> bool {
         for _type in types {
             if self._check(_type) {
@@ -354,7 +580,7 @@ impl Parser {
                 return true;
             }
         }
-        return false;
+        false
     }
 
     fn _check(&self, _type: &TokenType) -> bool {
@@ -364,14 +590,14 @@ impl Parser {
         if let Some(token) = self._peek() {
             return &token._type == _type;
         }
-        return false;
+        false
     }
 
     fn _advance(&self) -> Option<&Token> {
         if !self._is_end() {
             self.current.set(self.current.get() + 1);
         }
-        return self._previous();
+        self._previous()
     }
 
     fn _is_end(&self) -> bool {
@@ -399,6 +625,7 @@ impl Parser {
         Err(ParseError::new(self._peek(), message))
     }
 
+    #[allow(dead_code)]
     fn synchronize(&mut self) {
         self._advance();
         while !self._is_end() {
@@ -412,6 +639,7 @@ impl Parser {
                     TokenType::CLASS
                     | TokenType::FUN
                     | TokenType::VAR
+                    | TokenType::CONST
                     | TokenType::FOR
                     | TokenType::IF
                     | TokenType::WHILE
@@ -424,3 +652,46 @@ impl Parser {
         }
     }
 }
+
+// The implicit operand of `++` and `--`.
+fn one() -> RcExpr {
+    Literal::build(TokenLiteral::Int(1), None)
+}
+
+#[test]
+fn test_invalid_update_targets() {
+    use crate::scanner::Scanner;
+
+    for (source, message) in [
+        ("1 += 2;", "ParseError at line 1 column 4: Invalid assignment target."),
+        ("f()++;", "ParseError at line 1 column 5: Invalid assignment target."),
+        ("++(a);", "ParseError at line 1 column 2: Invalid assignment target."),
+        ("a ? b : c = d;", "ParseError at line 1 column 11: Invalid assignment target."),
+        ("a ? b;", "ParseError at line 1 column 6: Expect ':' after then branch of conditional expression."),
+    ] {
+        let err = Parser::new(Scanner::build(source).scan_tokens()).parse().unwrap_err();
+        assert_eq!(err.0, message, "{}", source);
+    }
+}
+
+#[test]
+fn test_import_export_syntax() {
+    use crate::scanner::Scanner;
+
+    let source = "/// Shared.\nexport var x = 1;\nimport \"m.lox\" as m;\nprint m.x.y;\n";
+    let stmts = Parser::new(Scanner::build(source).scan_tokens()).parse().unwrap();
+    let Stmt::Export(export) = stmts[0].as_ref() else { panic!("expected an export") };
+    let Stmt::Var(var) = export.declaration.as_ref() else { panic!("expected a var") };
+    assert_eq!(var.doc.as_deref(), Some("Shared."));
+
+    for (source, message) in [
+        ("{ export var x; }", "ParseError at line 1 column 8: Can only export top-level declarations."),
+        ("export print 1;", "ParseError at line 1 column 12: Expect 'fun', 'var' or 'const' after 'export'."),
+        ("import m as m;", "ParseError at line 1 column 8: Expect module path string after 'import'."),
+        ("import \"m.lox\" m;", "ParseError at line 1 column 16: Expect 'as' after module path."),
+        ("m.1;", "ParseError at line 1 column 3: Expect property name after '.'."),
+    ] {
+        let err = Parser::new(Scanner::build(source).scan_tokens()).parse().unwrap_err();
+        assert_eq!(err.0, message, "{}", source);
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+// Name of the root frame that top-level code runs in.
+const SCRIPT: &str = "script";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+    pub calls: usize,
+    // Time inside the function, including its callees. Recursive
+    // activations are only counted once.
+    pub total: Duration,
+    // Time inside the function itself.
+    pub own: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LineProfile {
+    // Times execution reached the line.
+    pub hits: usize,
+    // Time spent on the line itself, excluding nested statements and calls.
+    pub own: Duration,
+}
+
+struct Frame {
+    name: String,
+    start: Instant,
+    children: Duration,
+    // Depth of `current_lines` when the frame was entered.
+    lines: usize,
+}
+
+// Instrumenting profiler driven by the interpreter: every call goes through
+// `enter_function`/`exit_function` and every statement through
+// `enter_line`/`exit_line`.
+pub struct Profiler {
+    functions: HashMap<String, FunctionProfile>,
+    // By (file, line).
+    lines: HashMap<(usize, usize), LineProfile>,
+    // Own time per call path, e.g. "script;outer;inner".
+    stacks: HashMap<String, Duration>,
+    frames: Vec<Frame>,
+    // Open activations per function name, to spot recursion.
+    active: HashMap<String, usize>,
+    current_lines: Vec<(usize, usize)>,
+    // When time was last attributed to the current line.
+    mark: Instant,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            functions: HashMap::new(),
+            lines: HashMap::new(),
+            stacks: HashMap::new(),
+            frames: vec![Frame { name: SCRIPT.to_string(), start: now, children: Duration::ZERO, lines: 0 }],
+            active: HashMap::new(),
+            current_lines: vec![],
+            mark: now,
+        }
+    }
+
+    // Charges the time since the last event to the line being executed.
+    fn flush_line(&mut self) {
+        let now = Instant::now();
+        if let Some(line) = self.current_lines.last() {
+            self.lines.entry(*line).or_default().own += now - self.mark;
+        }
+        self.mark = now;
+    }
+
+    pub fn enter_line(&mut self, file: usize, line: usize) {
+        self.flush_line();
+        // A statement nested on its parent's line in the same frame is not
+        // a new hit.
+        let base = self.frames.last().map_or(0, |frame| frame.lines);
+        let nested = self.current_lines.len() > base && self.current_lines.last() == Some(&(file, line));
+        let hits = &mut self.lines.entry((file, line)).or_default().hits;
+        if !nested {
+            *hits += 1;
+        }
+        self.current_lines.push((file, line));
+    }
+
+    pub fn exit_line(&mut self) {
+        self.flush_line();
+        self.current_lines.pop();
+    }
+
+    pub fn enter_function(&mut self, name: &str) {
+        self.flush_line();
+        *self.active.entry(name.to_string()).or_default() += 1;
+        self.frames.push(Frame {
+            name: name.to_string(),
+            start: Instant::now(),
+            children: Duration::ZERO,
+            lines: self.current_lines.len(),
+        });
+    }
+
+    pub fn exit_function(&mut self) {
+        self.flush_line();
+        if self.frames.len() <= 1 {
+            return;
+        }
+        let path = self.path();
+        let frame = self.frames.pop().unwrap();
+        let elapsed = frame.start.elapsed();
+        let own = elapsed.saturating_sub(frame.children);
+        if let Some(parent) = self.frames.last_mut() {
+            parent.children += elapsed;
+        }
+        *self.stacks.entry(path).or_default() += own;
+
+        let active = self.active.get_mut(&frame.name).unwrap();
+        *active -= 1;
+        let outermost = *active == 0;
+        let profile = self.functions.entry(frame.name).or_default();
+        profile.calls += 1;
+        profile.own += own;
+        if outermost {
+            profile.total += elapsed;
+        }
+    }
+
+    fn path(&self) -> String {
+        let names: Vec<&str> = self.frames.iter().map(|frame| frame.name.as_str()).collect();
+        names.join(";")
+    }
+
+    // Closes any frames left open, e.g. by a runtime error, and returns
+    // what was recorded.
+    pub fn finish(mut self) -> Profile {
+        while self.frames.len() > 1 {
+            self.exit_function();
+        }
+        while !self.current_lines.is_empty() {
+            self.exit_line();
+        }
+        let script = &self.frames[0];
+        let elapsed = script.start.elapsed();
+        *self.stacks.entry(SCRIPT.to_string()).or_default() += elapsed.saturating_sub(script.children);
+        Profile { elapsed, functions: self.functions, lines: self.lines, stacks: self.stacks, files: HashMap::new() }
+    }
+}
+
+pub struct Profile {
+    pub elapsed: Duration,
+    pub functions: HashMap<String, FunctionProfile>,
+    // By (file, line); the script is file 0.
+    pub lines: HashMap<(usize, usize), LineProfile>,
+    pub stacks: HashMap<String, Duration>,
+    // Paths of the imported files that appear in `lines`.
+    pub files: HashMap<usize, String>,
+}
+
+impl Profile {
+    // One "frame;frame;frame microseconds" line per call path, as read by
+    // flamegraph.pl and inferno.
+    pub fn collapsed(&self) -> String {
+        let mut stacks: Vec<(&String, &Duration)> = self.stacks.iter().collect();
+        stacks.sort();
+        stacks
+            .iter()
+            .map(|(path, time)| format!("{} {}\n", path, time.as_micros()))
+            .collect()
+    }
+}
+
+fn millis(time: Duration) -> f64 {
+    time.as_secs_f64() * 1000.0
+}
+
+impl Display for Profile {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Profile: {:.3}ms total", millis(self.elapsed))?;
+
+        writeln!(f)?;
+        writeln!(f, "{:>10} {:>12} {:>12}  function", "calls", "total ms", "self ms")?;
+        let mut functions: Vec<(&String, &FunctionProfile)> = self.functions.iter().collect();
+        functions.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(b.0)));
+        for (name, profile) in functions {
+            writeln!(f, "{:>10} {:>12.3} {:>12.3}  {}", profile.calls, millis(profile.total), millis(profile.own), name)?;
+        }
+
+        writeln!(f)?;
+        write!(f, "{:>10} {:>12} {:>12}", "line", "hits", "self ms")?;
+        let mut lines: Vec<(&(usize, usize), &LineProfile)> = self.lines.iter().collect();
+        lines.sort_by(|a, b| b.1.own.cmp(&a.1.own).then(a.0.cmp(b.0)));
+        for ((file, line), profile) in lines {
+            let line = match self.files.get(file) {
+                Some(path) => format!("{}:{}", path, line),
+                None => line.to_string(),
+            };
+            write!(f, "\n{:>10} {:>12} {:>12.3}", line, profile.hits, millis(profile.own))?;
+        }
+        Ok(())
+    }
+}
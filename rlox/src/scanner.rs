@@ -1,222 +1,475 @@
+use unicode_ident::{is_xid_continue, is_xid_start};
+
+use crate::errors::LexError;
+use crate::interner::intern;
 use crate::token::{Token, TokenLiteral, TokenType, KEYWORDS};
+use std::collections::HashMap;
+use std::rc::Rc;
 use std::string::String;
 
+// Lexer over a source string. As an iterator it yields one token, or one
+// error for input it could not make sense of, at a time and ends with an
+// EOF token. With trivia on it also yields comments and runs of whitespace,
+// which the parser must not see.
 pub struct Scanner {
     source: String,
-    tokens: Vec<Token>,
+    // Byte offsets of the current lexeme into `source`.
     start: usize,
     current: usize,
+    // Chars before `current`. Token offsets count chars, not bytes.
+    position: usize,
     line: usize,
+    // String constants seen so far, so equal literals share one allocation.
+    constants: HashMap<String, Rc<str>>,
+    trivia: bool,
+    finished: bool,
+    // One entry per string interpolation being scanned: how many `{` are
+    // open inside it, so its closing `}` can be told apart.
+    interpolations: Vec<usize>,
+    file: usize,
 }
 
 impl Scanner {
-    pub fn build(_source: &String) -> Scanner {
+    pub fn build(_source: &str) -> Scanner {
         Scanner {
-            source: _source.clone(),
-            tokens: vec![],
+            source: _source.to_string(),
             start: 0,
             current: 0,
+            position: 0,
             line: 1,
+            constants: HashMap::new(),
+            trivia: false,
+            finished: false,
+            interpolations: vec![],
+            file: 0,
         }
     }
-    pub fn scan_tokens(mut self) -> Vec<Token> {
-        // self.source.split(" ").collect::<Vec<&str>>()
-        while !self.is_at_end() {
-            // We are at the beginning of the next lexeme.
-            self.start = self.current;
-            self.scan_token();
+    // Also yield COMMENT and WHITESPACE tokens.
+    pub fn with_trivia(mut self) -> Scanner {
+        self.trivia = true;
+        self
+    }
+    // Marks every token as coming from module `file`.
+    pub fn in_file(mut self, file: usize) -> Scanner {
+        self.file = file;
+        self
+    }
+    // Scans everything, reporting malformed input on stderr.
+    pub fn scan_tokens(self) -> Vec<Token> {
+        let (tokens, errors) = self.scan();
+        for error in errors {
+            eprintln!("{}", error);
         }
-        self.tokens.push(Token::build(
-            TokenType::EOF,
-            "",
-            TokenLiteral::Nil,
-            self.line,
-            self.current,
-        ));
-        return self.tokens;
+        tokens
+    }
+    // Scans everything, returning malformed input alongside the tokens.
+    pub fn scan(self) -> (Vec<Token>, Vec<LexError>) {
+        let mut tokens = vec![];
+        let mut errors = vec![];
+        for item in self {
+            match item {
+                Ok(token) => tokens.push(token),
+                Err(error) => errors.push(error),
+            }
+        }
+        (tokens, errors)
+    }
+    fn error(&self, message: String) -> Option<Result<Token, LexError>> {
+        Some(Err(LexError { message, line: self.line, offset: self.position }))
     }
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
-    fn scan_token(&mut self) -> Option<bool> {
-        if let Some(c) = self._advance() {
-            match c {
-                '(' => self._add_token(TokenType::LEFTPAREN),
-                ')' => self._add_token(TokenType::RIGHTPAREN),
-                '{' => self._add_token(TokenType::LEFTBRACE),
-                '}' => self._add_token(TokenType::RIGHTBRACE),
-                ',' => self._add_token(TokenType::COMMA),
-                '.' => self._add_token(TokenType::DOT),
-                '-' => self._add_token(TokenType::MINUS),
-                '+' => self._add_token(TokenType::PLUS),
-                ';' => self._add_token(TokenType::SEMICOLON),
-                '*' => self._add_token(TokenType::STAR),
-                '"' => self._string(),
-                '!' => {
-                    if self._match_char('=') {
-                        self._add_token(TokenType::BANGEQUAL);
-                    } else {
-                        self._add_token(TokenType::BANG);
-                    }
+    // The next token or error starting at `self.start`, or `None` for
+    // skipped trivia.
+    fn scan_token(&mut self) -> Option<Result<Token, LexError>> {
+        let c = self._advance()?;
+        let token = match c {
+            '(' => self._add_token(TokenType::LEFTPAREN),
+            ')' => self._add_token(TokenType::RIGHTPAREN),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
                 }
-                '=' => {
-                    if self._match_char('=') {
-                        self._add_token(TokenType::EQUALEQUAL);
-                    } else {
-                        self._add_token(TokenType::EQUAL);
-                    }
+                self._add_token(TokenType::LEFTBRACE)
+            }
+            '}' => {
+                if self.interpolations.last() == Some(&0) {
+                    // The end of an interpolated expression; the string goes on.
+                    self.interpolations.pop();
+                    return self._string();
                 }
-                '<' => {
-                    if self._match_char('=') {
-                        self._add_token(TokenType::LESSEQUAL);
-                    } else {
-                        self._add_token(TokenType::LESS);
-                    }
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth -= 1;
                 }
-                '>' => {
-                    if self._match_char('=') {
-                        self._add_token(TokenType::GREATEREQUAL);
-                    } else {
-                        self._add_token(TokenType::GREATER);
-                    }
+                self._add_token(TokenType::RIGHTBRACE)
+            }
+            ',' => self._add_token(TokenType::COMMA),
+            '.' => self._add_token(TokenType::DOT),
+            '-' => {
+                if self._match_char('-') {
+                    self._add_token(TokenType::MINUSMINUS)
+                } else if self._match_char('=') {
+                    self._add_token(TokenType::MINUSEQUAL)
+                } else {
+                    self._add_token(TokenType::MINUS)
+                }
+            }
+            '+' => {
+                if self._match_char('+') {
+                    self._add_token(TokenType::PLUSPLUS)
+                } else if self._match_char('=') {
+                    self._add_token(TokenType::PLUSEQUAL)
+                } else {
+                    self._add_token(TokenType::PLUS)
+                }
+            }
+            '?' => self._add_token(TokenType::QUESTION),
+            ':' => self._add_token(TokenType::COLON),
+            ';' => self._add_token(TokenType::SEMICOLON),
+            '*' => {
+                if self._match_char('*') {
+                    self._add_token(TokenType::STARSTAR)
+                } else if self._match_char('=') {
+                    self._add_token(TokenType::STAREQUAL)
+                } else {
+                    self._add_token(TokenType::STAR)
+                }
+            }
+            '%' => {
+                if self._match_char('=') {
+                    self._add_token(TokenType::PERCENTEQUAL)
+                } else {
+                    self._add_token(TokenType::PERCENT)
+                }
+            }
+            '&' => self._add_token(TokenType::AMPERSAND),
+            '|' => self._add_token(TokenType::PIPE),
+            '^' => self._add_token(TokenType::CARET),
+            '~' => {
+                if self._match_char('/') {
+                    self._add_token(TokenType::TILDESLASH)
+                } else {
+                    self._add_token(TokenType::TILDE)
+                }
+            }
+            '"' => return self._string(),
+            '!' => {
+                if self._match_char('=') {
+                    self._add_token(TokenType::BANGEQUAL)
+                } else {
+                    self._add_token(TokenType::BANG)
+                }
+            }
+            '=' => {
+                if self._match_char('=') {
+                    self._add_token(TokenType::EQUALEQUAL)
+                } else {
+                    self._add_token(TokenType::EQUAL)
+                }
+            }
+            '<' => {
+                if self._match_char('=') {
+                    self._add_token(TokenType::LESSEQUAL)
+                } else if self._match_char('<') {
+                    self._add_token(TokenType::LESSLESS)
+                } else {
+                    self._add_token(TokenType::LESS)
+                }
+            }
+            '>' => {
+                if self._match_char('=') {
+                    self._add_token(TokenType::GREATEREQUAL)
+                } else if self._match_char('>') {
+                    self._add_token(TokenType::GREATERGREATER)
+                } else {
+                    self._add_token(TokenType::GREATER)
                 }
-                '/' => {
-                    if self._match_char('/') {
-                        // A comment goes until the end of the line.
-                        while self._peek() != Some('\n') && !self.is_at_end() {
-                            self._advance();
-                        }
+            }
+            '/' => {
+                if self._match_char('/') {
+                    // `///`, but not `////`, documents the declaration below.
+                    let doc = self._peek() == Some('/') && self._peek_next() != Some('/');
+                    // A comment goes until the end of the line.
+                    while self._peek() != Some('\n') && !self.is_at_end() {
+                        self._advance();
+                    }
+                    if doc {
+                        self._add_token(TokenType::DOCCOMMENT)
+                    } else if !self.trivia {
+                        return None;
                     } else {
-                        self._add_token(TokenType::SLASH);
+                        self._add_token(TokenType::COMMENT)
                     }
+                } else if self._match_char('*') {
+                    return self.block_comment();
+                } else if self._match_char('=') {
+                    self._add_token(TokenType::SLASHEQUAL)
+                } else {
+                    self._add_token(TokenType::SLASH)
                 }
-                ' ' | '\r' | '\t' => {
-                    // Ignore whitespace.
-                }
-                '\n' => {
+            }
+            ' ' | '\r' | '\t' | '\n' => {
+                if c == '\n' {
                     self.line += 1;
                 }
-                _ => {
-                    if c.is_numeric() {
-                        self._number();
-                    } else if c.is_alphabetic() {
-                        self._identifier();
-                    } else {
-                        println!(
-                            "{}",
-                            format!("line: {}, Unexpected character: {}", self.line, c)
-                        );
-                        return Some(true);
+                while let Some(ch @ (' ' | '\r' | '\t' | '\n')) = self._peek() {
+                    if ch == '\n' {
+                        self.line += 1;
                     }
+                    self._advance();
+                }
+                if !self.trivia {
+                    return None;
                 }
+                self._add_token(TokenType::WHITESPACE)
             }
-        }
-        return Some(false);
+            _ => {
+                if c.is_ascii_digit() {
+                    return self._number(c);
+                } else if c == '_' || is_xid_start(c) {
+                    self._identifier()
+                } else {
+                    return self.error(format!("Unexpected character: {}", c));
+                }
+            }
+        };
+        Some(Ok(token))
     }
 
-    fn add_token(&mut self, token_type: TokenType, literal: TokenLiteral) {
-        let sub_string = self.source[self.start..self.current].to_owned();
-        self.tokens.push(Token::build(
+    fn add_token(&self, token_type: TokenType, literal: TokenLiteral) -> Token {
+        Token::build(
             token_type,
-            &sub_string,
+            &self.source[self.start..self.current],
             literal,
             self.line,
-            self.current,
-        ));
+            self.position,
+        )
     }
-    fn _identifier(&mut self) {
+    fn _identifier(&mut self) -> Token {
         while let Some(ch) = self._peek() {
-            if ch.is_alphanumeric() || ch == '_' {
+            if is_xid_continue(ch) {
                 self._advance();
             } else {
                 break;
             }
         }
-        let text = self.source[self.start..self.current].to_owned();
-        match KEYWORDS.lock().unwrap().get(&text) {
-            Some(t) => self.add_token(t.clone(), TokenLiteral::String(text)),
-            None => self.add_token(TokenType::IDENTIFIER, TokenLiteral::String(text)),
-        }
+        let text = &self.source[self.start..self.current];
+        let literal = TokenLiteral::String(intern(text).as_str());
+        let keyword = KEYWORDS.lock().unwrap().get(text).cloned();
+        self.add_token(keyword.unwrap_or(TokenType::IDENTIFIER), literal)
     }
-    fn _number(&mut self) {
-        while let Some(ch) = self._peek() {
-            if ch.is_numeric() {
-                self._advance();
-            } else {
-                break;
+    // `/* ... */`, which may nest and span lines.
+    fn block_comment(&mut self) -> Option<Result<Token, LexError>> {
+        let mut depth = 1;
+        while depth > 0 {
+            match self._advance() {
+                None => return self.error("Unterminated block comment.".to_string()),
+                Some('\n') => self.line += 1,
+                Some('/') if self._match_char('*') => depth += 1,
+                Some('*') if self._match_char('/') => depth -= 1,
+                Some(_) => {}
             }
         }
-        // Look for a fractional part.
-        let ch = self._peek().unwrap();
-        let chnext = self._peek_next().unwrap();
-        if ch == '.' && chnext.is_numeric() {
-            // Consume the "."
+        if !self.trivia {
+            return None;
+        }
+        Some(Ok(self._add_token(TokenType::COMMENT)))
+    }
+
+    // Decimal, `0x` hex or `0b` binary digits, with `_` allowed between
+    // digits. Decimals with a fraction or exponent are floats, the rest
+    // integers.
+    fn _number(&mut self, first: char) -> Option<Result<Token, LexError>> {
+        let radix = match (first, self._peek()) {
+            ('0', Some('x' | 'X')) => 16,
+            ('0', Some('b' | 'B')) => 2,
+            _ => 10,
+        };
+        if radix != 10 {
             self._advance();
-            while let Some(ch) = self._peek() {
-                if ch.is_numeric() {
+        }
+        self.digits(radix);
+        let mut float = false;
+        if radix == 10 {
+            // Look for a fractional part.
+            if self._peek() == Some('.') && self._peek_next().is_some_and(|ch| ch.is_ascii_digit()) {
+                self._advance();
+                self.digits(10);
+                float = true;
+            }
+            let mut rest = self.source[self.current..].chars();
+            if matches!(rest.next(), Some('e' | 'E')) {
+                let mut next = rest.next();
+                let signed = matches!(next, Some('+' | '-'));
+                if signed {
+                    next = rest.next();
+                }
+                if next.is_some_and(|ch| ch.is_ascii_digit()) {
                     self._advance();
-                } else {
-                    break;
+                    if signed {
+                        self._advance();
+                    }
+                    self.digits(10);
+                    float = true;
                 }
             }
         }
-        let var_number = self.source[self.start..self.current]
-            .parse::<f64>()
-            .unwrap();
-        self.add_token(TokenType::NUMBER, TokenLiteral::Number(var_number));
+
+        // Anything word-like straight after, e.g. `0b102` or `1_`, belongs
+        // to a malformed literal rather than starting a new token.
+        while self._peek().is_some_and(is_xid_continue) {
+            self._advance();
+        }
+        let text = &self.source[self.start..self.current];
+        let digits = if radix == 10 { text } else { &text[2..] };
+        let bytes = digits.as_bytes();
+        let separated = bytes.iter().enumerate().all(|(i, b)| {
+            let digit = |i: usize| bytes.get(i).is_some_and(|b| (*b as char).is_digit(radix));
+            *b != b'_' || (i > 0 && digit(i - 1) && digit(i + 1))
+        });
+        let digits = digits.replace('_', "");
+        let literal = if !separated {
+            None
+        } else if float {
+            digits.parse::<f64>().ok().map(TokenLiteral::Number)
+        } else if digits.chars().all(|ch| ch.is_digit(radix)) && !digits.is_empty() {
+            match i64::from_str_radix(&digits, radix) {
+                Ok(n) => Some(TokenLiteral::Int(n)),
+                Err(_) => return self.error(format!("Integer literal too large: {}.", text)),
+            }
+        } else {
+            None
+        };
+        match literal {
+            Some(literal) => Some(Ok(self.add_token(TokenType::NUMBER, literal))),
+            None => self.error(format!("Invalid number literal: {}.", text)),
+        }
+    }
+    // Consumes digits in `radix` and `_` separators.
+    fn digits(&mut self, radix: u32) {
+        while self._peek().is_some_and(|ch| ch.is_digit(radix) || ch == '_') {
+            self._advance();
+        }
     }
 
-    fn _string(&mut self) {
-        while let Some(ch) = self._peek() {
-            if ch != '"' && !self.is_at_end() {
-                if ch == '\n' {
-                    self.line += 1;
+    // String text from just after the opening `"`, or the `}` closing an
+    // interpolation, up to the closing `"` or the next `${`.
+    fn _string(&mut self) -> Option<Result<Token, LexError>> {
+        let mut text = String::new();
+        let mut invalid = None;
+        let kind = loop {
+            let Some(ch) = self._advance() else {
+                return self.error("Unterminated string.".to_string());
+            };
+            match ch {
+                '"' => break TokenType::STRING,
+                '$' if self._peek() == Some('{') => {
+                    self._advance();
+                    self.interpolations.push(0);
+                    break TokenType::INTERPOLATION;
+                }
+                '\\' => match self.escape() {
+                    Ok(ch) => text.push(ch),
+                    Err(message) => invalid = invalid.or(Some(message)),
+                },
+                ch => {
+                    if ch == '\n' {
+                        self.line += 1;
+                    }
+                    text.push(ch);
                 }
-                self._advance();
-            } else {
-                break;
             }
+        };
+        if let Some(message) = invalid {
+            return self.error(message);
         }
-        if self.is_at_end() {
-            println!("line {}, {}", self.line, "Unterminated string.");
-            return;
+        let constant = self.constants
+            .entry(text)
+            .or_insert_with_key(|text| Rc::from(text.as_str()))
+            .clone();
+        Some(Ok(self.add_token(kind, TokenLiteral::String(constant))))
+    }
+    // The character an escape sequence stands for, just after the `\`.
+    fn escape(&mut self) -> Result<char, String> {
+        // At the end of input `_string` reports the unterminated string.
+        let Some(ch) = self._advance() else {
+            return Ok('\0');
+        };
+        match ch {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            '\\' => Ok('\\'),
+            '"' => Ok('"'),
+            '$' => Ok('$'),
+            'u' => {
+                let mut digits = String::new();
+                let open = self._match_char('{');
+                while let Some(ch) = self._peek().filter(|ch| ch.is_ascii_hexdigit()) {
+                    digits.push(ch);
+                    self._advance();
+                }
+                let close = open && self._match_char('}');
+                let code = u32::from_str_radix(&digits, 16).ok().filter(|_| close && digits.len() <= 6);
+                code.and_then(char::from_u32).ok_or_else(|| format!("Invalid unicode escape: \\u{{{}}}.", digits))
+            }
+            ch => Err(format!("Invalid escape sequence: \\{}.", ch)),
         }
-        // The closing ".
-        self._advance();
-        // Trim the surrounding quotes.
-        let var_string = self.source[self.start + 1..self.current - 1].to_owned();
-        self.add_token(TokenType::STRING, TokenLiteral::String(var_string));
     }
-    fn _add_token(&mut self, token_type: TokenType) {
-        self.add_token(token_type, TokenLiteral::Nil);
+    fn _add_token(&self, token_type: TokenType) -> Token {
+        self.add_token(token_type, TokenLiteral::Nil)
     }
     fn _match_char(&mut self, expected: char) -> bool {
         if self.is_at_end() {
             return false;
         }
-        if self.source.chars().nth(self.current) != Some(expected) {
+        if self._peek() != Some(expected) {
             return false;
         }
-        self.current += 1;
-        return true;
+        self._advance();
+        true
     }
     fn _peek(&self) -> Option<char> {
         if self.is_at_end() {
             return Some('\0');
         }
-        self.source.chars().nth(self.current)
+        self.source[self.current..].chars().next()
     }
     fn _peek_next(&self) -> Option<char> {
-        if self.current + 1 >= self.source.len() {
-            return Some('\0');
-        }
-        self.source.chars().nth(self.current + 1)
+        let mut chars = self.source[self.current..].chars();
+        chars.next();
+        Some(chars.next().unwrap_or('\0'))
     }
     fn _advance(&mut self) -> Option<char> {
         // 获取最近的字符
-        let c: Option<char> = self.source.chars().nth(self.current);
-        self.current += 1;
-        c
+        let c = self.source[self.current..].chars().next()?;
+        self.current += c.len_utf8();
+        self.position += 1;
+        Some(c)
+    }
+}
+
+impl Iterator for Scanner {
+    type Item = Result<Token, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.is_at_end() {
+            // We are at the beginning of the next lexeme.
+            self.start = self.current;
+            if let Some(item) = self.scan_token() {
+                return Some(item.map(|token| Token { file: self.file, ..token }));
+            }
+        }
+        if !self.interpolations.is_empty() {
+            self.interpolations.clear();
+            return self.error("Unterminated string interpolation.".to_string());
+        }
+        if self.finished {
+            return None;
+        }
+        self.finished = true;
+        let eof = Token::build(TokenType::EOF, "", TokenLiteral::Nil, self.line, self.position);
+        Some(Ok(Token { file: self.file, ..eof }))
     }
 }
 
@@ -279,3 +532,179 @@ fn test3() {
     assert_eq!(tokens[9]._type, TokenType::SEMICOLON);
     assert_eq!(tokens[10]._type, TokenType::EOF);
 }
+
+#[test]
+fn test_shared_constants() {
+    let source: String = "var a = \"lox\"; var b = \"lox\"; a = b;".to_string();
+    let tokens = Scanner::build(&source).scan_tokens();
+
+    let (TokenLiteral::String(first), TokenLiteral::String(second)) = (&tokens[3].literal, &tokens[8].literal) else {
+        panic!("expected string literals");
+    };
+    assert!(Rc::ptr_eq(first, second));
+    assert_eq!(tokens[1].symbol, tokens[10].symbol);
+    assert_eq!(tokens[6].symbol, tokens[12].symbol);
+}
+
+#[test]
+fn test_streaming_errors() {
+    let mut scanner = Scanner::build("var @ = 1;");
+    assert_eq!(scanner.next().unwrap().unwrap()._type, TokenType::VAR);
+    let error = scanner.next().unwrap().unwrap_err();
+    assert_eq!(error, LexError { message: "Unexpected character: @".to_string(), line: 1, offset: 5 });
+    let rest: Vec<TokenType> = scanner.map(|item| item.unwrap()._type).collect();
+    assert_eq!(rest, [TokenType::EQUAL, TokenType::NUMBER, TokenType::SEMICOLON, TokenType::EOF]);
+}
+
+#[test]
+fn test_trivia() {
+    let source = "var a = 1; // one\n\n  print a;\n";
+    let tokens: Vec<Token> = Scanner::build(source).with_trivia().map(|item| item.unwrap()).collect();
+    let text: String = tokens.iter().map(|token| token.lexeme.as_ref()).collect();
+    assert_eq!(text, source);
+    let comment = tokens.iter().find(|token| token._type == TokenType::COMMENT).unwrap();
+    assert_eq!((comment.lexeme.as_ref(), comment.line, comment.start()), ("// one", 1, 11));
+    let whitespace = tokens.iter().filter(|token| token._type == TokenType::WHITESPACE).count();
+    assert_eq!(whitespace, 7);
+    let plain = Scanner::build(source).filter(|item| item.is_ok()).count();
+    assert_eq!(plain, tokens.len() - whitespace - 1);
+}
+
+#[test]
+fn test_unicode_source() {
+    let source = "var 名前 = \"こんにちは 😀\"; var _ünï = 名前;";
+    let tokens = Scanner::build(source).scan_tokens();
+    let kinds: Vec<&TokenType> = tokens.iter().map(|token| &token._type).collect();
+    assert_eq!(kinds[..5], [&TokenType::VAR, &TokenType::IDENTIFIER, &TokenType::EQUAL, &TokenType::STRING, &TokenType::SEMICOLON]);
+    assert_eq!(tokens[1].lexeme.as_ref(), "名前");
+    assert_eq!(tokens[3].literal, TokenLiteral::String("こんにちは 😀".into()));
+    assert_eq!(tokens[6].lexeme.as_ref(), "_ünï");
+    assert_eq!(tokens[8].symbol, tokens[1].symbol);
+    // Offsets count chars, not bytes.
+    assert_eq!((tokens[1].start(), tokens[1].offset), (4, 6));
+    assert_eq!((tokens[3].start(), tokens[3].offset), (9, 18));
+    assert_eq!(tokens[9].offset, source.chars().count());
+}
+
+#[test]
+fn test_unicode_errors() {
+    let (tokens, errors) = Scanner::build("print 😀 + ٣;\n\"unterminated 字").scan();
+    let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+    assert_eq!(messages, ["line 1, Unexpected character: 😀", "line 1, Unexpected character: ٣", "line 2, Unterminated string."]);
+    assert_eq!(errors[0].offset, 7);
+    let kinds: Vec<&TokenType> = tokens.iter().map(|token| &token._type).collect();
+    assert_eq!(kinds, [&TokenType::PRINT, &TokenType::PLUS, &TokenType::SEMICOLON, &TokenType::EOF]);
+}
+
+#[test]
+fn test_large_source() {
+    // Megabytes of multi-byte text scan in linear time.
+    let source = "var 变量 = \"值😀\"; // 注释\n".repeat(50_000);
+    let tokens = Scanner::build(&source).scan_tokens();
+    assert_eq!(tokens.len(), 50_000 * 5 + 1);
+    assert_eq!(tokens.last().unwrap().line, 50_001);
+    assert_eq!(tokens.last().unwrap().offset, source.chars().count());
+}
+
+#[test]
+fn test_string_escapes() {
+    let tokens = Scanner::build(r#""a\"b\\c\nd\te\u{1F600}\u{5b57}\$""#).scan_tokens();
+    assert_eq!(tokens[0].literal, TokenLiteral::String("a\"b\\c\nd\te😀字$".into()));
+
+    let (tokens, errors) = Scanner::build(r#""\q" "\u{110000}" "\u{zz}" "\u41" "ok""#).scan();
+    let messages: Vec<String> = errors.iter().map(|error| error.message.clone()).collect();
+    assert_eq!(
+        messages,
+        ["Invalid escape sequence: \\q.", "Invalid unicode escape: \\u{110000}.", "Invalid unicode escape: \\u{}.", "Invalid unicode escape: \\u{41}."]
+    );
+    assert_eq!(tokens[0].literal, TokenLiteral::String("ok".into()));
+}
+
+#[test]
+fn test_interpolation_tokens() {
+    let source = r#""a ${b + "c ${d}"} e ${ {} }""#;
+    let tokens = Scanner::build(source).scan_tokens();
+    let parts: Vec<(&TokenType, &str)> = tokens.iter().map(|token| (&token._type, token.lexeme.as_ref())).collect();
+    assert_eq!(
+        parts,
+        [
+            (&TokenType::INTERPOLATION, "\"a ${"),
+            (&TokenType::IDENTIFIER, "b"),
+            (&TokenType::PLUS, "+"),
+            (&TokenType::INTERPOLATION, "\"c ${"),
+            (&TokenType::IDENTIFIER, "d"),
+            (&TokenType::STRING, "}\""),
+            (&TokenType::INTERPOLATION, "} e ${"),
+            (&TokenType::LEFTBRACE, "{"),
+            (&TokenType::RIGHTBRACE, "}"),
+            (&TokenType::STRING, "}\""),
+            (&TokenType::EOF, ""),
+        ]
+    );
+    assert_eq!(tokens[6].literal, TokenLiteral::String(" e ".into()));
+
+    let (_, errors) = Scanner::build("\"a ${b").scan();
+    assert_eq!(errors[0].message, "Unterminated string interpolation.");
+}
+
+#[test]
+fn test_number_literals() {
+    let source = "0xFF 0b1010 1_000_000 1e9 2.5E-3 1_0.2_5 7.method 9223372036854775807";
+    let literals: Vec<TokenLiteral> = Scanner::build(source)
+        .scan_tokens()
+        .into_iter()
+        .filter(|token| token._type == TokenType::NUMBER)
+        .map(|token| token.literal)
+        .collect();
+    assert_eq!(
+        literals,
+        [
+            TokenLiteral::Int(255),
+            TokenLiteral::Int(10),
+            TokenLiteral::Int(1_000_000),
+            TokenLiteral::Number(1e9),
+            TokenLiteral::Number(2.5e-3),
+            TokenLiteral::Number(10.25),
+            TokenLiteral::Int(7),
+            TokenLiteral::Int(i64::MAX),
+        ]
+    );
+
+    let (_, errors) = Scanner::build("0x 0b102 1_ 1__0 0x_1 1e 12abc 9223372036854775808").scan();
+    let messages: Vec<String> = errors.iter().map(|error| error.message.clone()).collect();
+    assert_eq!(
+        messages,
+        [
+            "Invalid number literal: 0x.",
+            "Invalid number literal: 0b102.",
+            "Invalid number literal: 1_.",
+            "Invalid number literal: 1__0.",
+            "Invalid number literal: 0x_1.",
+            "Invalid number literal: 1e.",
+            "Invalid number literal: 12abc.",
+            "Integer literal too large: 9223372036854775808.",
+        ]
+    );
+}
+
+#[test]
+fn test_block_comments() {
+    let source = "/* one\n /* nested\n */ still */ a\n//// plain\n/// doc\nb /* c";
+    let (tokens, errors) = Scanner::build(source).scan();
+    let kinds: Vec<(&TokenType, &str, usize)> =
+        tokens.iter().map(|token| (&token._type, token.lexeme.as_ref(), token.line)).collect();
+    assert_eq!(
+        kinds,
+        [
+            (&TokenType::IDENTIFIER, "a", 3),
+            (&TokenType::DOCCOMMENT, "/// doc", 5),
+            (&TokenType::IDENTIFIER, "b", 6),
+            (&TokenType::EOF, "", 6),
+        ]
+    );
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].to_string(), "line 6, Unterminated block comment.");
+
+    let trivia = Scanner::build("/* a */b").with_trivia().scan_tokens();
+    assert_eq!((&trivia[0]._type, trivia[0].lexeme.as_ref()), (&TokenType::COMMENT, "/* a */"));
+}
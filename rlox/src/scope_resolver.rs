@@ -1,8 +1,9 @@
-use std::cell::Cell;
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use std::{cell::{Cell, RefCell}, collections::HashMap, rc::Rc};
 
 use crate::ast;
+use crate::errors::{ResolveError, Severity};
 use crate::ast::*;
+use crate::interner::Symbol;
 use crate::interpreter::Interpreter;
 use crate::token::Token;
 use ast::{ExprVisitable, ExprVisitor, RcExpr, RcStmt, StmtVisitable, StmtVisitor};
@@ -20,6 +21,9 @@ impl_expr_visitable! {
     (Assign, assign),
     (Logical, logical),
     (Call, call),
+    (Update, update),
+    (Conditional, conditional),
+    (Get, get),
 }
 impl_stmt_visitable! {
     <()>,
@@ -31,24 +35,112 @@ impl_stmt_visitable! {
     (While, while),
     (Function, function),
     (Return, return),
+    (Import, import),
+    (Export, export),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeclarationKind {
+    Variable,
+    Constant,
+    Function,
+    Parameter,
+    Module,
+}
+
+// A name introduced by `var`, `const`, `fun`, `import` or a parameter
+// list. `depth` is the
+// number of enclosing local scopes, so globals are at depth 0.
+#[derive(Debug, Clone)]
+pub struct Declaration {
+    pub name: Token,
+    pub kind: DeclarationKind,
+    pub depth: usize,
+    // The declaration as it reads in the source, e.g. "fun add(a, b)".
+    pub detail: String,
+    // Its `///` doc comment, if any.
+    pub doc: Option<Rc<str>>,
+}
+
+// A use of a name, with the declaration it resolves to if there is one.
+#[derive(Debug, Clone)]
+pub struct Reference {
+    pub name: Token,
+    pub declaration: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    pub declarations: Vec<Declaration>,
+    pub references: Vec<Reference>,
+}
+
+#[derive(Debug, Default)]
+pub struct Resolution {
+    pub errors: Vec<ResolveError>,
+    pub warnings: Vec<ResolveError>,
+    pub symbols: SymbolTable,
+}
+
+#[derive(Clone, Copy)]
+struct Binding {
+    defined: bool,
+    // Read, assigned or captured anywhere in its scope.
+    used: bool,
+    declaration: usize,
 }
 
 pub struct ScopeResolver<'a> {
-    pub scopes: RefCell<Vec<HashMap<String, bool>>>,
-    interpreter: RefCell<&'a mut Interpreter>,
+    scopes: RefCell<Vec<HashMap<Symbol, Binding>>>,
+    // Globals are bound late, so references to them are only resolved
+    // once every top-level declaration has been seen.
+    globals: RefCell<HashMap<Symbol, usize>>,
+    symbols: RefCell<SymbolTable>,
+    errors: RefCell<Vec<ResolveError>>,
+    warnings: RefCell<Vec<ResolveError>>,
+    // Number of function bodies around the current statement.
+    functions: Cell<usize>,
+    interpreter: &'a Interpreter,
 }
 
 impl<'a> ScopeResolver<'a>
 where
     Self: ExprVisitor<()> + StmtVisitor<()>,
 {
-    pub fn new(interpreter: &'a mut Interpreter) -> Self {
+    pub fn new(interpreter: &'a Interpreter) -> Self {
         Self {
             scopes: RefCell::new(Vec::new()),
-            interpreter: interpreter.into(),
+            globals: RefCell::new(HashMap::new()),
+            symbols: RefCell::new(SymbolTable::default()),
+            errors: RefCell::new(Vec::new()),
+            warnings: RefCell::new(Vec::new()),
+            functions: Cell::new(0),
+            interpreter,
+        }
+    }
+    // Everything found while resolving, with global references bound to
+    // the last top-level declaration of their name.
+    pub fn finish(self) -> Resolution {
+        let mut symbols = self.symbols.into_inner();
+        let globals = self.globals.into_inner();
+        for reference in symbols.references.iter_mut() {
+            if reference.declaration.is_none() {
+                reference.declaration = globals.get(&reference.name.symbol).copied();
+            }
         }
+        Resolution { errors: self.errors.into_inner(), warnings: self.warnings.into_inner(), symbols }
+    }
+    fn error(&self, token: &Token, message: &str) {
+        self.errors.borrow_mut().push(ResolveError { token: token.clone(), message: message.to_string(), severity: Severity::Error });
+    }
+    fn warning(&self, token: &Token, message: &str) {
+        self.warnings.borrow_mut().push(ResolveError { token: token.clone(), message: message.to_string(), severity: Severity::Warning });
     }
     pub fn resolve(&self, statements: &Vec<RcStmt>) {
+        let after_return = statements.iter().position(|stmt| matches!(stmt.as_ref(), Stmt::Return(_))).and_then(|index| statements.get(index + 1));
+        if let Some(token) = after_return.and_then(|stmt| stmt.token()) {
+            self.warning(token, "Unreachable code.");
+        }
         for stmt in statements {
             self.resolve_stmt(stmt.clone());
         }
@@ -56,26 +148,75 @@ where
     fn begin_scope(&self) {
         self.scopes.borrow_mut().push(HashMap::new());
     }
+    // Warns about the scope's locals that were never used, in the order
+    // they were declared. Names starting with `_` are exempt.
     fn end_scope(&self) {
-        self.scopes.borrow_mut().pop();
+        let scope = self.scopes.borrow_mut().pop().unwrap_or_default();
+        let mut unused: Vec<usize> = scope.values().filter(|binding| !binding.used).map(|binding| binding.declaration).collect();
+        unused.sort_unstable();
+        let symbols = self.symbols.borrow();
+        for declaration in unused.into_iter().map(|index| &symbols.declarations[index]) {
+            if declaration.name.lexeme.starts_with('_') {
+                continue;
+            }
+            let kind = match declaration.kind {
+                DeclarationKind::Variable => "Local variable",
+                DeclarationKind::Constant => "Local constant",
+                DeclarationKind::Function => "Local function",
+                DeclarationKind::Parameter => "Parameter",
+                DeclarationKind::Module => "Module",
+            };
+            self.warning(&declaration.name, &format!("{} '{}' is never used.", kind, declaration.name.lexeme));
+        }
+    }
+    // Reading a local is an error while its declaration is still running.
+    fn check_initialized(&self, name: &Token) {
+        let uninitialized = self.scopes.borrow().last().and_then(|scope| scope.get(&name.symbol)).is_some_and(|binding| !binding.defined);
+        if uninitialized {
+            self.error(name, "Can't read local variable in its own initializer.");
+        }
+    }
+    // Assigning to a `const` is an error wherever it can be seen from here.
+    // Globals declared later are left to the interpreter.
+    fn check_assignable(&self, name: &Token) {
+        let local = self.scopes.borrow().iter().rev().find_map(|scope| scope.get(&name.symbol).map(|binding| binding.declaration));
+        let Some(declaration) = local.or_else(|| self.globals.borrow().get(&name.symbol).copied()) else {
+            return;
+        };
+        let symbols = self.symbols.borrow();
+        let declaration = &symbols.declarations[declaration];
+        if declaration.kind == DeclarationKind::Constant {
+            let (line, column) = (declaration.name.line, declaration.name.offset);
+            self.error(name, &format!("Can't assign to constant '{}' declared at line {} column {}.", name.lexeme, line, column));
+        }
     }
     fn resolve_local(&self, expr: RcExpr, name: Token) {
-        let borrowed_scope = self.scopes.borrow();
-        for (i, scope) in borrowed_scope.iter().enumerate().rev() {
-            if scope.contains_key(&name.lexeme) {
-                self.interpreter.borrow_mut().resolve(expr, borrowed_scope.len() - 1 - i);
+        let mut borrowed_scope = self.scopes.borrow_mut();
+        let depth = borrowed_scope.len();
+        for (i, scope) in borrowed_scope.iter_mut().enumerate().rev() {
+            if let Some(binding) = scope.get_mut(&name.symbol) {
+                binding.used = true;
+                self.interpreter.resolve(expr, depth - 1 - i);
+                self.reference(name, Some(binding.declaration));
                 return;
             }
         }
+        self.reference(name, None);
+    }
+    fn reference(&self, name: Token, declaration: Option<usize>) {
+        self.symbols.borrow_mut().references.push(Reference { name, declaration });
     }
     fn resolve_function(&self, func: &Function) {
+        self.functions.set(self.functions.get() + 1);
         self.begin_scope();
+        let detail = format!("parameter of {}", func.name.lexeme);
         for param in func.params.iter() {
-            self.declare(&param);
-            self.define(&param);
+            self.declare(param, DeclarationKind::Parameter, detail.clone(), None);
+            self.define(param);
         }
         self.resolve_stmt(func.body.clone());
         self.end_scope();
+        self.functions.set(self.functions.get() - 1);
     }
     fn resolve_stmt(&self, stmt: RcStmt) {
         <ast::Stmt as Clone>::clone(&stmt).accept(self);
@@ -83,20 +224,28 @@ where
     fn resolve_expr(&self, expr: RcExpr) {
         <ast::Expr as Clone>::clone(&expr).accept(self);
     }
-    fn declare(&self, token: &Token) {
-        if self.scopes.borrow().is_empty() {
-            return;
-        }
-        if let Some(scope) = self.scopes.borrow_mut().last_mut() {
-            scope.insert(token.lexeme.clone(), false);
+    fn declare(&self, token: &Token, kind: DeclarationKind, detail: String, doc: Option<Rc<str>>) {
+        let mut symbols = self.symbols.borrow_mut();
+        let declaration = symbols.declarations.len();
+        let mut scopes = self.scopes.borrow_mut();
+        symbols.declarations.push(Declaration { name: token.clone(), kind, depth: scopes.len(), detail, doc });
+        match scopes.last_mut() {
+            Some(scope) => {
+                if scope.contains_key(&token.symbol) {
+                    self.error(token, "Already a variable with this name in this scope.");
+                }
+                scope.insert(token.symbol, Binding { defined: false, used: false, declaration });
+            }
+            None => {
+                self.globals.borrow_mut().insert(token.symbol, declaration);
+            }
         }
     }
     fn define(&self, token: &Token) {
-        if self.scopes.borrow().is_empty() {
-            return;
-        }
         if let Some(scope) = self.scopes.borrow_mut().last_mut() {
-            scope.insert(token.lexeme.clone(), true);
+            if let Some(binding) = scope.get_mut(&token.symbol) {
+                binding.defined = true;
+            }
         }
     }
 }
@@ -118,19 +267,13 @@ impl ExprVisitor<()> for ScopeResolver<'_> {
     }
 
     fn visit_variable(&self, expr: &ast::Variable) {
-        let borrowed_scope = self.scopes.borrow();
-        if !borrowed_scope.is_empty() {
-            if let Some(scope) = borrowed_scope.last() {
-                if let Some(false) = scope.get(&expr.name.lexeme) {
-                    panic!("Can't read local variable in its own initializer.")
-                }
-            }
-        }
+        self.check_initialized(&expr.name);
         self.resolve_local(Rc::new(ast::Expr::Variable(expr.clone())), expr.name.clone());
     }
 
     fn visit_assign(&self, expr: &ast::Assign) {
         self.resolve_expr(expr.value.clone());
+        self.check_assignable(&expr.name);
         self.resolve_local(Rc::new(ast::Expr::Assign(expr.clone())), expr.name.clone());
     }
 
@@ -145,6 +288,23 @@ impl ExprVisitor<()> for ScopeResolver<'_> {
             self.resolve_expr(arg.clone());
         }
     }
+
+    fn visit_update(&self, expr: &ast::Update) {
+        self.check_initialized(&expr.name);
+        self.check_assignable(&expr.name);
+        self.resolve_expr(expr.value.clone());
+        self.resolve_local(Rc::new(ast::Expr::Update(expr.clone())), expr.name.clone());
+    }
+
+    fn visit_conditional(&self, expr: &ast::Conditional) {
+        self.resolve_expr(expr.condition.clone());
+        self.resolve_expr(expr.then_branch.clone());
+        self.resolve_expr(expr.else_branch.clone());
+    }
+
+    fn visit_get(&self, expr: &ast::Get) {
+        self.resolve_expr(expr.object.clone());
+    }
 }
 
 impl StmtVisitor<()> for ScopeResolver<'_> {
@@ -157,8 +317,8 @@ impl StmtVisitor<()> for ScopeResolver<'_> {
     }
 
     fn visit_var(&self, stmt: &ast::Var) {
-        self.declare(&stmt.name);
-        // declare(stmt.name);
+        let (kind, keyword) = if stmt.constant { (DeclarationKind::Constant, "const") } else { (DeclarationKind::Variable, "var") };
+        self.declare(&stmt.name, kind, format!("{} {}", keyword, stmt.name.lexeme), stmt.doc.clone());
         if let Some(initializer) = &stmt.initializer {
             self.resolve_expr(initializer.clone());
         }
@@ -185,16 +345,80 @@ impl StmtVisitor<()> for ScopeResolver<'_> {
     }
 
     fn visit_function(&self, stmt: &ast::Function) {
-        self.declare(&stmt.name);
+        let params: Vec<&str> = stmt.params.iter().map(|param| param.lexeme.as_ref()).collect();
+        let detail = format!("fun {}({})", stmt.name.lexeme, params.join(", "));
+        self.declare(&stmt.name, DeclarationKind::Function, detail, stmt.doc.clone());
         self.define(&stmt.name);
         self.resolve_function(stmt);
     }
 
     fn visit_return(&self, stmt: &ast::Return) {
+        if self.functions.get() == 0 {
+            self.error(&stmt.keyword, "Can't return from top-level code.");
+        }
         if let Some(value) = &stmt.value {
             self.resolve_expr(value.clone());
         }
     }
+
+    fn visit_import(&self, stmt: &ast::Import) {
+        let detail = format!("import {} as {}", stmt.path.lexeme, stmt.name.lexeme);
+        self.declare(&stmt.name, DeclarationKind::Module, detail, None);
+        self.define(&stmt.name);
+    }
+
+    fn visit_export(&self, stmt: &ast::Export) {
+        self.resolve_stmt(stmt.declaration.clone());
+    }
 }
 
 // ----------------------------------------------------------------
+
+#[test]
+fn test_diagnostics() {
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    let resolve = |source: &str| {
+        let stmts = Parser::new(Scanner::build(source).scan_tokens()).parse().unwrap();
+        let interpreter = Interpreter::new();
+        let resolver = ScopeResolver::new(&interpreter);
+        resolver.resolve(&stmts);
+        let resolution = resolver.finish();
+        let messages = |list: &[ResolveError]| list.iter().map(|error| (error.token.line, error.message.clone())).collect::<Vec<_>>();
+        (messages(&resolution.errors), messages(&resolution.warnings))
+    };
+
+    let source = "return 1;
+{ var a = 1; var a = 2; print a; }
+{ var b = b; }
+fun f(x, _y) {
+  var unused = 1;
+  return x;
+  print x;
+}";
+    let (errors, warnings) = resolve(source);
+    assert_eq!(
+        errors,
+        [
+            (1, "Can't return from top-level code.".to_string()),
+            (2, "Already a variable with this name in this scope.".to_string()),
+            (3, "Can't read local variable in its own initializer.".to_string()),
+        ]
+    );
+    assert_eq!(
+        warnings,
+        [
+            (2, "Unreachable code.".to_string()),
+            (7, "Unreachable code.".to_string()),
+            (5, "Local variable 'unused' is never used.".to_string()),
+        ]
+    );
+
+    let (errors, warnings) = resolve("fun g(n) { var n = 1; fun h() { return n; } return h; }\n{ import \"m.lox\" as m; const c = 1; }");
+    assert!(errors.is_empty());
+    assert_eq!(
+        warnings,
+        [(1, "Parameter 'n' is never used.".to_string()), (2, "Module 'm' is never used.".to_string()), (2, "Local constant 'c' is never used.".to_string())]
+    );
+}
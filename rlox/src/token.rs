@@ -3,9 +3,13 @@ use std::cmp::Eq;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::rc::Rc;
 use std::string::String;
 use std::sync::Mutex;
 
+use crate::interner::{intern, Symbol};
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum TokenType {
     // Single-character tokens.
@@ -20,6 +24,13 @@ pub enum TokenType {
     SEMICOLON,
     SLASH,
     STAR,
+    PERCENT,    // %
+    AMPERSAND,  // &
+    PIPE,       // |
+    CARET,      // ^
+    TILDE,      // ~
+    QUESTION,   // ?
+    COLON,      // :
 
     // One or two character tokens.
     BANG, // !
@@ -32,11 +43,29 @@ pub enum TokenType {
     LESSEQUAL, // <=
     AND, // and
     OR, // or
+    TILDESLASH, // ~/ (integer division)
+    LESSLESS, // <<
+    GREATERGREATER, // >>
+    STARSTAR, // **
+    PLUSPLUS, // ++
+    MINUSMINUS, // --
+    PLUSEQUAL, // +=
+    MINUSEQUAL, // -=
+    STAREQUAL, // *=
+    SLASHEQUAL, // /=
+    PERCENTEQUAL, // %=
 
     // Literals.
     IDENTIFIER, 
     STRING,
     NUMBER,
+    // The text of a string up to a `${`, e.g. `"Hello ${`. The embedded
+    // expression follows, then the rest of the string as another
+    // INTERPOLATION or, for the last piece, a STRING starting at the `}`.
+    INTERPOLATION,
+    // Never scanned: the parser wraps each interpolated expression in it
+    // to convert the value to a string.
+    STRINGIFY,
 
     // Keywords.
     CLASS,
@@ -53,6 +82,16 @@ pub enum TokenType {
     TRUE,
     VAR,
     WHILE,
+    IMPORT,
+    EXPORT,
+    CONST,
+
+    // Trivia, only kept for tooling.
+    COMMENT,
+    WHITESPACE,
+    // `/// text`, always scanned. The parser attaches it to the `fun`, `var`
+    // or `const` declaration that follows.
+    DOCCOMMENT,
 
     EOF,
 }
@@ -63,6 +102,14 @@ macro_rules! op_to_tt {
     [-] => { TokenType::MINUS };
     [*] => { TokenType::STAR };
     [/] => { TokenType::SLASH };
+    [%] => { TokenType::PERCENT };
+    [**] => { TokenType::STARSTAR };
+    [&] => { TokenType::AMPERSAND };
+    [|] => { TokenType::PIPE };
+    [^] => { TokenType::CARET };
+    [~] => { TokenType::TILDE };
+    [<<] => { TokenType::LESSLESS };
+    [>>] => { TokenType::GREATERGREATER };
     [!] => { TokenType::BANG };
     [=] => { TokenType::EQUAL };
     [>] => { TokenType::GREATER };
@@ -91,14 +138,18 @@ pub static KEYWORDS: Lazy<Mutex<HashMap<String, TokenType>>> = Lazy::new(|| {
         ("true".to_string(), TokenType::TRUE),
         ("var".to_string(), TokenType::VAR),
         ("while".to_string(), TokenType::WHILE),
+        ("import".to_string(), TokenType::IMPORT),
+        ("export".to_string(), TokenType::EXPORT),
+        ("const".to_string(), TokenType::CONST),
     ]);
     Mutex::new(m)
 });
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenLiteral {
+    Int(i64),
     Number(f64),
-    String(String),
+    String(Rc<str>),
     Bool(bool),
     Nil,
 }
@@ -110,6 +161,7 @@ impl Eq for TokenLiteral {
 impl Hash for TokenLiteral {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         match self {
+            TokenLiteral::Int(n) => n.hash(state),
             TokenLiteral::Number(n) => n.to_bits().hash(state),
             TokenLiteral::String(s) => s.hash(state),
             TokenLiteral::Bool(b) => b.hash(state),
@@ -121,6 +173,7 @@ impl Hash for TokenLiteral {
 impl std::fmt::Display for TokenLiteral {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            TokenLiteral::Int(n) => write!(f, "{}", n),
             TokenLiteral::Number(n) => write!(f, "{}", n),
             TokenLiteral::String(s) => write!(f, "{}", s),
             TokenLiteral::Bool(b) => write!(f, "{}", b),
@@ -129,23 +182,62 @@ impl std::fmt::Display for TokenLiteral {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Token {
     pub _type: TokenType,
-    pub lexeme: String,
+    pub lexeme: Rc<str>,
     pub literal: TokenLiteral,
     pub line: usize,
     pub offset: usize,
+    // Interned lexeme. Literal and trivia tokens get the empty symbol so
+    // that their text never enters the identifier table.
+    pub symbol: Symbol,
+    // Which loaded source the token came from: 0 for the script, then one
+    // id per imported module. Keeps look-alike tokens of different files
+    // apart as keys of the resolver's table.
+    pub file: usize,
+}
+
+// Hashes the interned symbol instead of the lexeme, so resolved-variable
+// lookups never hash identifier text. Equal tokens still hash equally.
+impl Hash for Token {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self._type.hash(state);
+        self.symbol.hash(state);
+        self.line.hash(state);
+        self.offset.hash(state);
+        self.file.hash(state);
+    }
 }
 
 impl Token {
     pub fn build(token_type: TokenType, lexeme: &str, literal: TokenLiteral, line: usize, offset: usize) -> Token {
+        let symbol = match token_type {
+            TokenType::STRING
+            | TokenType::INTERPOLATION
+            | TokenType::NUMBER
+            | TokenType::COMMENT
+            | TokenType::WHITESPACE
+            | TokenType::DOCCOMMENT => intern(""),
+            _ => intern(lexeme),
+        };
         Token {
             _type: token_type,
-            lexeme: String::from(lexeme),
+            lexeme: Rc::from(lexeme),
             literal,
             line,
             offset,
+            symbol,
+            file: 0,
         }
     }
+    // Char index the token starts at; `offset` points just past it.
+    pub fn start(&self) -> usize {
+        self.offset.saturating_sub(self.lexeme.chars().count())
+    }
+    // Line the token starts on. `line` is where it ends, which differs for
+    // multi-line strings and whitespace.
+    pub fn start_line(&self) -> usize {
+        self.line - self.lexeme.matches('\n').count()
+    }
 }
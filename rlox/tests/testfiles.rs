@@ -1,4 +1,8 @@
+use jlox_rust::ast::LoxValue;
+use jlox_rust::formatter::format_source;
 use jlox_rust::Lox;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 #[test]
 fn test_print() {
@@ -34,4 +38,150 @@ fn test_closure() {
 fn test_binding() {
     let mut lox = Lox::new();
     lox.run_file("./samples/binding.lox");
-}
\ No newline at end of file
+}
+#[test]
+fn test_bench_programs() {
+    let expected = [
+        ("fib", LoxValue::Int(2584)),
+        ("binary_trees", LoxValue::Int(2044)),
+        ("method_calls", LoxValue::Int(4000)),
+        ("zoo", LoxValue::Int(121000)),
+        ("loops", LoxValue::Int(24007500)),
+    ];
+    for (name, value) in expected {
+        let mut lox = Lox::new();
+        lox.run_file(&format!("./benches/lox/{}.lox", name));
+        assert!(!lox.had_runtime_error);
+        let result = lox.interpreter().environment.borrow().get("result".into()).unwrap();
+        assert_eq!(result, value, "{}", name);
+    }
+
+    let mut lox = Lox::new();
+    lox.run_file("./benches/lox/string_building.lox");
+    let env = lox.interpreter().environment.borrow();
+    let LoxValue::String(result) = env.get("result".into()).unwrap() else {
+        panic!("expected a string");
+    };
+    assert_eq!(result.len(), 2000);
+    assert_eq!(env.get("same".into()).unwrap(), LoxValue::Int(2000));
+}
+
+#[test]
+fn test_coverage() {
+    let mut lox = Lox::new();
+    lox.interpreter().start_coverage();
+    let source = "fun sign(n) {\n  if (n > 0) {\n    return 1;\n  } else {\n    return -1;\n  }\n}\nvar a = sign(1);\nvar b = sign(2);\n".to_string();
+    lox.run(&source);
+    let coverage = lox.interpreter().finish_coverage().unwrap();
+
+    let file = &coverage.files[0];
+    let lines: Vec<(usize, usize)> = file.lines.iter().map(|(line, hits)| (*line, *hits)).collect();
+    assert_eq!(lines, vec![(1, 1), (2, 2), (3, 2), (5, 0), (8, 1), (9, 1)]);
+    assert_eq!(file.lines_hit(), 5);
+
+    let lcov = coverage.lcov();
+    assert!(lcov.starts_with("TN:\nSF:script\nDA:1,1\n"));
+    assert!(lcov.contains("DA:5,0\nDA:8,1\nDA:9,1\nLF:6\nLH:5\nend_of_record\n"));
+    assert!(coverage.to_string().contains("#####:    5:     return -1;"));
+}
+
+#[test]
+fn test_coverage_of_modules() {
+    let mut lox = Lox::new();
+    lox.interpreter().set_print_handler(Some(Box::new(|_: &str| {})));
+    lox.interpreter().start_coverage();
+    lox.run_file("./tests/modules/main.lox");
+    let coverage = lox.interpreter().finish_coverage().unwrap();
+
+    let files: Vec<(&str, Vec<(usize, usize)>)> = coverage
+        .files
+        .iter()
+        .map(|file| (file.path.as_str(), file.lines.iter().map(|(line, hits)| (*line, *hits)).collect()))
+        .collect();
+    assert_eq!(
+        files,
+        [
+            ("./tests/modules/main.lox", vec![(1, 1), (2, 1), (4, 1), (5, 1), (6, 1), (7, 1)]),
+            ("./tests/modules/counter.lox", vec![(1, 1), (4, 1), (6, 1), (7, 2), (10, 1)]),
+        ]
+    );
+    assert!(coverage.lcov().contains("SF:./tests/modules/counter.lox\nDA:1,1\n"));
+}
+
+#[test]
+fn test_format_is_idempotent() {
+    let dirs = ["./samples", "./benches/lox"];
+    for dir in dirs {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let source = std::fs::read_to_string(&path).unwrap();
+            let formatted = format_source(&source).unwrap();
+            assert_eq!(format_source(&formatted).unwrap(), formatted, "{}", path.display());
+        }
+    }
+}
+
+#[test]
+fn test_dump_tokens() {
+    let mut lox = Lox::new();
+    let dump = lox.dump_tokens("print \"hi\"; // greet\n$", true);
+    let expected = [
+        "1:0..5       PRINT        \"print\"",
+        "1:5..6       WHITESPACE   \" \"",
+        "1:6..10      STRING       \"\\\"hi\\\"\" \"hi\"",
+        "1:10..11     SEMICOLON    \";\"",
+        "1:11..12     WHITESPACE   \" \"",
+        "1:12..20     COMMENT      \"// greet\"",
+        "1:20..21     WHITESPACE   \"\\n\"",
+        "2:22         ERROR        Unexpected character: $",
+        "2:22..22     EOF          \"\"",
+    ];
+    assert_eq!(dump.lines().collect::<Vec<_>>(), expected);
+    assert!(lox.had_error);
+}
+
+#[test]
+fn test_modules() {
+    let mut lox = Lox::new();
+    let printed = Rc::new(RefCell::new(vec![]));
+    let sink = printed.clone();
+    lox.interpreter().set_print_handler(Some(Box::new(move |text: &str| sink.borrow_mut().push(text.to_string()))));
+    lox.run_file("./tests/modules/main.lox");
+    assert!(!lox.had_runtime_error, "{:?}", lox.last_error);
+    // Both paths name one file, which ran once; exports are read live.
+    assert_eq!(*printed.borrow(), ["loading counter"]);
+    let env = lox.interpreter().environment.borrow();
+    assert_eq!(env.get("count".into()).unwrap(), LoxValue::Int(2));
+    assert_eq!(env.get("same".into()).unwrap(), LoxValue::Bool(true));
+    drop(env);
+
+    let mut lox = Lox::new();
+    lox.interpreter().set_module_path(vec!["./tests/modules/lib".into()]);
+    lox.run("import \"greeting.lox\" as greeting;\nvar message = greeting.greet(\"you\");");
+    let message = lox.interpreter().environment.borrow().get("message".into()).unwrap();
+    assert_eq!(message, LoxValue::String("hello, you".into()));
+
+    // An import in a module's function is relative to that module, even
+    // when it runs after the module has loaded.
+    let mut lox = Lox::new();
+    lox.run("import \"tests/modules/lib/lazy.lox\" as lazy;\nvar message = lazy.load();");
+    assert!(!lox.had_runtime_error, "{:?}", lox.last_error);
+    let message = lox.interpreter().environment.borrow().get("message".into()).unwrap();
+    assert_eq!(message, LoxValue::String("hello, later".into()));
+
+    for (source, error) in [
+        ("import \"tests/modules/cycle_a.lox\" as a;", "Import cycle: tests/modules/cycle_a.lox -> tests/modules/cycle_b.lox -> tests/modules/cycle_a.lox."),
+        ("import \"missing.lox\" as m;", "Can't find module 'missing.lox'."),
+        ("import \"tests/modules/broken.lox\" as m;", "In module 'tests/modules/broken.lox': ParseError at line 1 column 12: Expect variable name."),
+        ("import \"tests/modules/counter.lox\" as c;\nprint c.hidden;", "Module 'tests/modules/counter.lox' has no export 'hidden'."),
+        ("var x = 1;\nprint x.y;", "Only modules have properties."),
+        // A module's top level can't reach the importer's globals.
+        ("var secret = 1;\nimport \"tests/modules/isolated.lox\" as m;", "Undefined variable 'secret'."),
+    ] {
+        let mut lox = Lox::new();
+        lox.run(source);
+        assert!(lox.had_runtime_error, "{}", source);
+        let message = lox.last_error.unwrap();
+        assert!(message.contains(error), "{}", message);
+    }
+}